@@ -0,0 +1,744 @@
+/*  asm.rs
+ *  Assembler for the Octo language (https://github.com/JohnEarnest/Octo),
+ *  limited to the plain CHIP-8 instruction set this interpreter runs.
+ *
+ *  Supported: labels (`: name`), `:alias`, `:const`, `:macro`, `:calc`,
+ *  `:byte`, `:org`, `:call`, `loop`/`while`/`again`, `if ... then` and
+ *  `if ... begin ... else ... end`, and raw bytes for sprite data. As in Octo,
+ *  an identifier in statement position is a subroutine call, labels may be
+ *  used before they are defined, and `:calc` evaluates right to left without
+ *  operator precedence. Output starts at 0x200 with no implied `jump main`. */
+
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::error;
+use std::fmt;
+use std::io;
+use std::io::prelude::*;
+
+use chip8::{MEMORY_SIZE, PROGRAM_START};
+use instruction::Instruction;
+use number;
+
+/// An assembly error, tagged with the 1-based source line it occurred on.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AsmError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl error::Error for AsmError {}
+
+/// An assembled program.
+pub struct Assembly {
+    /// The ROM image, to be loaded at `PROGRAM_START`
+    pub bytes: Vec<u8>,
+    /// Every label and its address
+    pub symbols: BTreeMap<String, u16>,
+}
+
+impl Assembly {
+    /** Writes the symbol table, one `ADDR NAME` pair per line, sorted by name */
+    pub fn write_symbols<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        for (name, addr) in self.symbols.iter() {
+            writeln!(out, "0x{:03X} {}", addr, name)?;
+        }
+        Ok(())
+    }
+}
+
+/** Assembles Octo source into a ROM image */
+pub fn assemble(source: &str) -> Result<Assembly, AsmError> {
+    let mut asm = Assembler::new(source);
+    asm.run()?;
+    asm.finish()
+}
+
+#[derive(Debug, Clone)]
+struct Token {
+    text: String,
+    line: usize,
+    /// How many macro expansions produced this token; 0 for source tokens
+    depth: usize,
+}
+
+/// Macro expansions may nest this deep before the assembler assumes the
+/// macro is recursive.
+const MAX_MACRO_DEPTH: usize = 64;
+
+struct Macro {
+    params: Vec<String>,
+    body: Vec<Token>,
+}
+
+/// A label reference to fill into the low 12 bits of the word at `addr`.
+struct Fixup {
+    addr: u16,
+    name: String,
+    line: usize,
+}
+
+enum Control {
+    /// `loop`, with the `while` exit jumps that still need patching
+    Loop { start: u16, breaks: Vec<u16> },
+    /// `if ... begin`, with the jump over the block
+    If { jump: u16 },
+    /// `else`, with the jump over the else block
+    Else { jump: u16 },
+}
+
+/// The operand of an `if`/`while` condition.
+#[derive(Clone, Copy)]
+enum Operand {
+    Reg(u8),
+    Byte(u8),
+}
+
+struct Condition {
+    x: u8,
+    op: &'static str,
+    rhs: Option<Operand>,
+}
+
+impl Condition {
+    /** The skip instruction that skips the next statement when the condition holds */
+    fn skip_if_true(&self) -> Instruction {
+        self.skip(true)
+    }
+
+    /** The skip instruction that skips the next statement unless the condition holds */
+    fn skip_if_false(&self) -> Instruction {
+        self.skip(false)
+    }
+
+    fn skip(&self, when: bool) -> Instruction {
+        let x = self.x;
+        // "skip when equal" is the CHIP-8 primitive for `==` holding
+        let equal = (self.op == "==" || self.op == "key") == when;
+        match (self.op, self.rhs) {
+            ("key", _) | ("-key", _) => if equal { Instruction::Skp { x } } else { Instruction::Sknp { x } },
+            (_, Some(Operand::Reg(y))) => if equal { Instruction::SeVxVy { x, y } } else { Instruction::SneVxVy { x, y } },
+            (_, Some(Operand::Byte(nn))) => if equal { Instruction::SeVxByte { x, nn } } else { Instruction::SneVxByte { x, nn } },
+            (_, None) => unreachable!(),
+        }
+    }
+}
+
+struct Assembler {
+    tokens: VecDeque<Token>,
+    line: usize,
+    /// Expansion depth of the last token read
+    depth: usize,
+    here: u16,
+    /// Program bytes, starting at `PROGRAM_START`
+    bytes: Vec<u8>,
+    labels: HashMap<String, u16>,
+    consts: HashMap<String, i64>,
+    aliases: HashMap<String, u8>,
+    macros: HashMap<String, Macro>,
+    fixups: Vec<Fixup>,
+    control: Vec<Control>,
+}
+
+/** Splits source into whitespace-separated tokens, dropping `#` comments.
+ *  Braces are always tokens of their own. */
+fn tokenize(source: &str) -> VecDeque<Token> {
+    let mut tokens = VecDeque::new();
+    for (n, line) in source.lines().enumerate() {
+        let code = match line.find('#') {
+            Some(pos) => &line[..pos],
+            None => line,
+        };
+        for word in code.split_whitespace() {
+            let mut rest = word;
+            while !rest.is_empty() {
+                let cut = match rest.find(['{', '}']) {
+                    Some(0) => 1,
+                    Some(pos) => pos,
+                    None => rest.len(),
+                };
+                tokens.push_back(Token { text: rest[..cut].to_string(), line: n + 1, depth: 0 });
+                rest = &rest[cut..];
+            }
+        }
+    }
+    tokens
+}
+
+fn is_identifier(text: &str) -> bool {
+    let mut chars = text.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => {},
+        _ => return false,
+    }
+    chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+/** Register index for `v0`-`vF` (either case) */
+fn parse_register(text: &str) -> Option<u8> {
+    let mut chars = text.chars();
+    match (chars.next(), chars.next(), chars.next()) {
+        (Some('v'), Some(d), None) | (Some('V'), Some(d), None) => d.to_digit(16).map(|d| d as u8),
+        _ => None,
+    }
+}
+
+impl Assembler {
+    fn new(source: &str) -> Assembler {
+        Assembler {
+            tokens: tokenize(source),
+            line: 1,
+            depth: 0,
+            here: PROGRAM_START,
+            bytes: Vec::new(),
+            labels: HashMap::new(),
+            consts: HashMap::new(),
+            aliases: HashMap::new(),
+            macros: HashMap::new(),
+            fixups: Vec::new(),
+            control: Vec::new(),
+        }
+    }
+
+    fn error<T, S: Into<String>>(&self, message: S) -> Result<T, AsmError> {
+        Err(AsmError { line: self.line, message: message.into() })
+    }
+
+    fn next(&mut self) -> Result<String, AsmError> {
+        match self.tokens.pop_front() {
+            Some(token) => {
+                self.line = token.line;
+                self.depth = token.depth;
+                Ok(token.text)
+            },
+            None => self.error("unexpected end of input"),
+        }
+    }
+
+    fn peek(&self) -> Option<&str> {
+        self.tokens.front().map(|t| t.text.as_str())
+    }
+
+    fn expect(&mut self, text: &str) -> Result<(), AsmError> {
+        let token = self.next()?;
+        if token != text {
+            return self.error(format!("expected `{}`, found `{}`", text, token));
+        }
+        Ok(())
+    }
+
+    fn emit_byte(&mut self, byte: u8) -> Result<(), AsmError> {
+        let offset = (self.here - PROGRAM_START) as usize;
+        if self.here as usize >= MEMORY_SIZE {
+            return self.error(format!("program does not fit in memory (at 0x{:X})", self.here));
+        }
+        if offset >= self.bytes.len() {
+            self.bytes.resize(offset + 1, 0);
+        }
+        self.bytes[offset] = byte;
+        self.here += 1;
+        Ok(())
+    }
+
+    fn emit(&mut self, instr: Instruction) -> Result<(), AsmError> {
+        let word = instr.encode();
+        self.emit_byte((word >> 8) as u8)?;
+        self.emit_byte(word as u8)
+    }
+
+    /** Emits `instr` with a 12-bit address that may not be known yet */
+    fn emit_addr<F: Fn(u16) -> Instruction>(&mut self, target: Result<u16, String>, instr: F) -> Result<(), AsmError> {
+        match target {
+            Ok(addr) => self.emit(instr(addr)),
+            Err(name) => {
+                self.fixups.push(Fixup { addr: self.here, name, line: self.line });
+                self.emit(instr(0))
+            },
+        }
+    }
+
+    /** Fills the 12-bit address field of the word at `addr` */
+    fn patch(&mut self, addr: u16, target: u16) {
+        let offset = (addr - PROGRAM_START) as usize;
+        self.bytes[offset] = (self.bytes[offset] & 0xF0) | ((target >> 8) as u8 & 0x0F);
+        self.bytes[offset + 1] = target as u8;
+    }
+
+    fn register(&mut self) -> Result<u8, AsmError> {
+        let token = self.next()?;
+        self.as_register(&token).map_or_else(|| self.error(format!("expected a register, found `{}`", token)), Ok)
+    }
+
+    fn as_register(&self, token: &str) -> Option<u8> {
+        parse_register(token).or_else(|| self.aliases.get(token).cloned())
+    }
+
+    /** Value of a number, constant or already-defined label */
+    fn lookup(&self, token: &str) -> Option<i64> {
+        number::parse(token, 10)
+            .or_else(|| self.consts.get(token).cloned())
+            .or_else(|| self.labels.get(token).map(|&a| a as i64))
+    }
+
+    fn value(&mut self) -> Result<i64, AsmError> {
+        let token = self.next()?;
+        if token == "{" {
+            return self.calc();
+        }
+        self.lookup(&token).map_or_else(|| self.error(format!("undefined name `{}`", token)), Ok)
+    }
+
+    fn byte(&mut self) -> Result<u8, AsmError> {
+        let value = self.value()?;
+        self.to_byte(value)
+    }
+
+    /** Accepts both unsigned (0..=255) and signed (-128..=-1) byte values */
+    fn to_byte(&self, value: i64) -> Result<u8, AsmError> {
+        if !(-128..=255).contains(&value) {
+            return self.error(format!("{} does not fit in a byte", value));
+        }
+        Ok(value as u8)
+    }
+
+    fn nibble(&mut self) -> Result<u8, AsmError> {
+        let value = self.value()?;
+        if !(0..=15).contains(&value) {
+            return self.error(format!("{} does not fit in a nibble", value));
+        }
+        Ok(value as u8)
+    }
+
+    /** A 12-bit address, or `Err(name)` for a label that is not defined yet */
+    fn address(&mut self) -> Result<Result<u16, String>, AsmError> {
+        let token = match self.peek() {
+            Some(token) => token.to_string(),
+            None => return self.error("unexpected end of input"),
+        };
+        if self.lookup(&token).is_none() && token != "{" && is_identifier(&token) {
+            self.next()?;
+            return Ok(Err(token));
+        }
+        let value = self.value()?;
+        if !(0..=0xFFF).contains(&value) {
+            return self.error(format!("address {} is out of range", value));
+        }
+        Ok(Ok(value as u16))
+    }
+
+    /** Evaluates a `:calc` expression up to the closing brace */
+    fn calc(&mut self) -> Result<i64, AsmError> {
+        let value = self.expr()?;
+        self.expect("}")?;
+        Ok(value)
+    }
+
+    /** Octo expressions have no precedence and associate to the right */
+    fn expr(&mut self) -> Result<i64, AsmError> {
+        let lhs = self.term()?;
+        let op = match self.peek() {
+            Some(op @ "+") | Some(op @ "-") | Some(op @ "*") | Some(op @ "/") | Some(op @ "%") |
+            Some(op @ "&") | Some(op @ "|") | Some(op @ "^") | Some(op @ "<<") | Some(op @ ">>") |
+            Some(op @ "min") | Some(op @ "max") => op.to_string(),
+            _ => return Ok(lhs),
+        };
+        self.next()?;
+        let rhs = self.expr()?;
+        Ok(match op.as_str() {
+            "+" => lhs.wrapping_add(rhs),
+            "-" => lhs.wrapping_sub(rhs),
+            "*" => lhs.wrapping_mul(rhs),
+            "/" | "%" if rhs == 0 => return self.error("division by zero"),
+            "/" => lhs / rhs,
+            "%" => lhs % rhs,
+            "&" => lhs & rhs,
+            "|" => lhs | rhs,
+            "^" => lhs ^ rhs,
+            "<<" => lhs << (rhs & 63),
+            ">>" => lhs >> (rhs & 63),
+            "min" => lhs.min(rhs),
+            _ => lhs.max(rhs),
+        })
+    }
+
+    fn term(&mut self) -> Result<i64, AsmError> {
+        let token = self.next()?;
+        match token.as_str() {
+            "(" => {
+                let value = self.expr()?;
+                self.expect(")")?;
+                Ok(value)
+            },
+            "-" => Ok(-self.term()?),
+            "~" => Ok(!self.term()?),
+            "HERE" => Ok(self.here as i64),
+            _ => self.lookup(&token).map_or_else(|| self.error(format!("undefined name `{}`", token)), Ok),
+        }
+    }
+
+    fn define(&mut self, kind: &str) -> Result<String, AsmError> {
+        let name = self.next()?;
+        if !is_identifier(&name) || parse_register(&name).is_some() {
+            return self.error(format!("`{}` is not a valid {} name", name, kind));
+        }
+        if self.labels.contains_key(&name) || self.consts.contains_key(&name) ||
+           self.aliases.contains_key(&name) || self.macros.contains_key(&name) {
+            return self.error(format!("`{}` is already defined", name));
+        }
+        Ok(name)
+    }
+
+    fn condition(&mut self) -> Result<Condition, AsmError> {
+        let x = self.register()?;
+        let op = match self.next()?.as_str() {
+            "==" => "==",
+            "!=" => "!=",
+            "key" => "key",
+            "-key" => "-key",
+            other => return self.error(format!("unsupported comparison `{}`", other)),
+        };
+        let rhs = if op == "==" || op == "!=" {
+            let token = self.peek().unwrap_or("").to_string();
+            match self.as_register(&token) {
+                Some(y) => {
+                    self.next()?;
+                    Some(Operand::Reg(y))
+                },
+                None => Some(Operand::Byte(self.byte()?)),
+            }
+        } else {
+            None
+        };
+        Ok(Condition { x, op, rhs })
+    }
+
+    fn run(&mut self) -> Result<(), AsmError> {
+        while !self.tokens.is_empty() {
+            self.statement()?;
+        }
+        Ok(())
+    }
+
+    fn statement(&mut self) -> Result<(), AsmError> {
+        let token = self.next()?;
+        match token.as_str() {
+            ":" => {
+                let name = self.define("label")?;
+                self.labels.insert(name, self.here);
+            },
+            ":alias" => {
+                let name = self.define("alias")?;
+                let reg = self.register()?;
+                self.aliases.insert(name, reg);
+            },
+            ":const" => {
+                let name = self.define("constant")?;
+                let value = self.value()?;
+                self.consts.insert(name, value);
+            },
+            ":calc" => {
+                let name = self.define("constant")?;
+                self.expect("{")?;
+                let value = self.calc()?;
+                self.consts.insert(name, value);
+            },
+            ":macro" => {
+                let name = self.define("macro")?;
+                let mut params = Vec::new();
+                loop {
+                    let param = self.next()?;
+                    if param == "{" {
+                        break;
+                    }
+                    params.push(param);
+                }
+                let mut body = Vec::new();
+                let mut depth = 1;
+                loop {
+                    let token = match self.tokens.pop_front() {
+                        Some(token) => token,
+                        None => return self.error(format!("unterminated macro `{}`", name)),
+                    };
+                    if token.text == "{" {
+                        depth += 1;
+                    } else if token.text == "}" {
+                        depth -= 1;
+                        if depth == 0 {
+                            break;
+                        }
+                    }
+                    body.push(token);
+                }
+                self.macros.insert(name, Macro { params, body });
+            },
+            ":byte" => {
+                let byte = self.byte()?;
+                self.emit_byte(byte)?;
+            },
+            ":org" => {
+                let addr = self.value()?;
+                if addr < PROGRAM_START as i64 || addr >= MEMORY_SIZE as i64 {
+                    return self.error(format!(":org 0x{:X} is outside program memory", addr));
+                }
+                self.here = addr as u16;
+            },
+            ":call" => {
+                let target = self.address()?;
+                self.emit_addr(target, Instruction::Call)?;
+            },
+            "return" | ";" => self.emit(Instruction::Ret)?,
+            "clear" => self.emit(Instruction::Cls)?,
+            "native" => {
+                let target = self.address()?;
+                self.emit_addr(target, Instruction::Sys)?;
+            },
+            "jump" => {
+                let target = self.address()?;
+                self.emit_addr(target, Instruction::Jp)?;
+            },
+            "jump0" => {
+                let target = self.address()?;
+                self.emit_addr(target, Instruction::JpV0)?;
+            },
+            "bcd" => {
+                let x = self.register()?;
+                self.emit(Instruction::LdBVx { x })?;
+            },
+            "save" => {
+                let x = self.register()?;
+                self.emit(Instruction::LdIVx { x })?;
+            },
+            "load" => {
+                let x = self.register()?;
+                self.emit(Instruction::LdVxI { x })?;
+            },
+            "sprite" => {
+                let x = self.register()?;
+                let y = self.register()?;
+                let n = self.nibble()?;
+                self.emit(Instruction::Drw { x, y, n })?;
+            },
+            "delay" | "buzzer" => {
+                self.expect(":=")?;
+                let x = self.register()?;
+                self.emit(if token == "delay" { Instruction::LdDtVx { x } } else { Instruction::LdStVx { x } })?;
+            },
+            "i" | "I" => self.index_statement()?,
+            "if" => {
+                let cond = self.condition()?;
+                match self.next()?.as_str() {
+                    "then" => self.emit(cond.skip_if_false())?,
+                    "begin" => {
+                        self.emit(cond.skip_if_true())?;
+                        self.control.push(Control::If { jump: self.here });
+                        self.emit(Instruction::Jp(0))?;
+                    },
+                    other => return self.error(format!("expected `then` or `begin`, found `{}`", other)),
+                }
+            },
+            "else" => {
+                let jump = match self.control.pop() {
+                    Some(Control::If { jump }) => jump,
+                    _ => return self.error("`else` without `if ... begin`"),
+                };
+                self.control.push(Control::Else { jump: self.here });
+                self.emit(Instruction::Jp(0))?;
+                let here = self.here;
+                self.patch(jump, here);
+            },
+            "end" => {
+                let jump = match self.control.pop() {
+                    Some(Control::If { jump }) | Some(Control::Else { jump }) => jump,
+                    _ => return self.error("`end` without `if ... begin`"),
+                };
+                let here = self.here;
+                self.patch(jump, here);
+            },
+            "loop" => self.control.push(Control::Loop { start: self.here, breaks: Vec::new() }),
+            "while" => {
+                let cond = self.condition()?;
+                let here = self.here + 2;
+                match self.control.iter_mut().rev().find(|c| matches!(**c, Control::Loop { .. })) {
+                    Some(&mut Control::Loop { ref mut breaks, .. }) => breaks.push(here),
+                    _ => return self.error("`while` outside of `loop`"),
+                }
+                self.emit(cond.skip_if_true())?;
+                self.emit(Instruction::Jp(0))?;
+            },
+            "again" => {
+                let (start, breaks) = match self.control.pop() {
+                    Some(Control::Loop { start, breaks }) => (start, breaks),
+                    _ => return self.error("`again` without `loop`"),
+                };
+                self.emit(Instruction::Jp(start))?;
+                let here = self.here;
+                for jump in breaks {
+                    self.patch(jump, here);
+                }
+            },
+            _ => {
+                if let Some(x) = self.as_register(&token) {
+                    return self.register_statement(x);
+                }
+                if self.macros.contains_key(&token) {
+                    return self.expand(&token);
+                }
+                if let Some(value) = self.lookup(&token) {
+                    if number::parse(&token, 10).is_some() || self.consts.contains_key(&token) {
+                        let byte = self.to_byte(value)?;
+                        return self.emit_byte(byte);
+                    }
+                }
+                if !is_identifier(&token) {
+                    return self.error(format!("unexpected `{}`", token));
+                }
+                // Any other identifier is a call to a (possibly later) label
+                let target = match self.labels.get(&token) {
+                    Some(&addr) => Ok(addr),
+                    None => Err(token),
+                };
+                self.emit_addr(target, Instruction::Call)?;
+            },
+        }
+        Ok(())
+    }
+
+    /** `i := addr`, `i := hex vX`, `i += vX` */
+    fn index_statement(&mut self) -> Result<(), AsmError> {
+        match self.next()?.as_str() {
+            ":=" => {
+                if self.peek() == Some("hex") {
+                    self.next()?;
+                    let x = self.register()?;
+                    return self.emit(Instruction::LdFVx { x });
+                }
+                let target = self.address()?;
+                self.emit_addr(target, Instruction::LdI)
+            },
+            "+=" => {
+                let x = self.register()?;
+                self.emit(Instruction::AddIVx { x })
+            },
+            other => self.error(format!("unsupported operation `i {}`", other)),
+        }
+    }
+
+    /** Statements that start with a register: `vX op ...` */
+    fn register_statement(&mut self, x: u8) -> Result<(), AsmError> {
+        let op = self.next()?;
+        let rhs = self.peek().unwrap_or("").to_string();
+
+        if op == ":=" {
+            match rhs.as_str() {
+                "random" => {
+                    self.next()?;
+                    let nn = self.byte()?;
+                    return self.emit(Instruction::Rnd { x, nn });
+                },
+                "key" => {
+                    self.next()?;
+                    return self.emit(Instruction::LdVxK { x });
+                },
+                "delay" => {
+                    self.next()?;
+                    return self.emit(Instruction::LdVxDt { x });
+                },
+                _ => {},
+            }
+        }
+
+        if let Some(y) = self.as_register(&rhs) {
+            self.next()?;
+            let instr = match op.as_str() {
+                ":=" => Instruction::LdVxVy { x, y },
+                "|=" => Instruction::Or { x, y },
+                "&=" => Instruction::And { x, y },
+                "^=" => Instruction::Xor { x, y },
+                "+=" => Instruction::AddVxVy { x, y },
+                "-=" => Instruction::Sub { x, y },
+                "=-" => Instruction::Subn { x, y },
+                ">>=" => Instruction::Shr { x, y },
+                "<<=" => Instruction::Shl { x, y },
+                _ => return self.error(format!("unsupported operation `v{:x} {} v{:x}`", x, op, y)),
+            };
+            return self.emit(instr);
+        }
+
+        let instr = match op.as_str() {
+            ":=" => Instruction::LdVxByte { x, nn: self.byte()? },
+            "+=" => Instruction::AddVxByte { x, nn: self.byte()? },
+            "-=" => Instruction::AddVxByte { x, nn: self.byte()?.wrapping_neg() },
+            _ => return self.error(format!("unsupported operation `v{:x} {} {}`", x, op, rhs)),
+        };
+        self.emit(instr)
+    }
+
+    /** Substitutes macro arguments into its body and queues the result */
+    fn expand(&mut self, name: &str) -> Result<(), AsmError> {
+        let depth = self.depth + 1;
+        if depth > MAX_MACRO_DEPTH {
+            return self.error(format!("macro `{}` nests more than {} expansions deep; is it recursive?",
+                                      name, MAX_MACRO_DEPTH));
+        }
+        let count = self.macros[name].params.len();
+        let mut args = HashMap::new();
+        for i in 0..count {
+            let arg = self.next()?;
+            args.insert(self.macros[name].params[i].clone(), arg);
+        }
+        let line = self.line;
+        let body = &self.macros[name].body;
+        for token in body.iter().rev() {
+            let text = args.get(&token.text).cloned().unwrap_or_else(|| token.text.clone());
+            self.tokens.push_front(Token { text, line, depth });
+        }
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<Assembly, AsmError> {
+        if let Some(open) = self.control.last() {
+            let what = match *open {
+                Control::Loop { .. } => "`loop` without `again`",
+                _ => "`if ... begin` without `end`",
+            };
+            return self.error(what);
+        }
+
+        let fixups = ::std::mem::take(&mut self.fixups);
+        for fixup in fixups {
+            match self.labels.get(&fixup.name).cloned() {
+                Some(target) => self.patch(fixup.addr, target),
+                None => return Err(AsmError { line: fixup.line, message: format!("undefined label `{}`", fixup.name) }),
+            }
+        }
+
+        Ok(Assembly {
+            bytes: self.bytes,
+            symbols: self.labels.into_iter().collect(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recursive_macro_is_an_error() {
+        let source = ":macro forever {\n  clear\n  forever\n}\n\nforever\n";
+        let error = assemble(source).err().unwrap();
+        assert_eq!(error.line, 6);
+        assert!(error.message.contains("`forever`"), "{}", error.message);
+    }
+
+    #[test]
+    fn nested_macros_expand() {
+        let source = ":macro inner x { v0 := x }\n:macro outer { inner 5 inner 6 }\nouter\n";
+        assert_eq!(assemble(source).unwrap().bytes, vec![0x60, 0x05, 0x60, 0x06]);
+    }
+}
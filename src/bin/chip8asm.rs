@@ -0,0 +1,74 @@
+/*  chip8asm.rs
+ *  Assembles Octo source into a .ch8 ROM */
+
+extern crate chip8remu;
+
+use std::env;
+use std::fs::File;
+use std::io::prelude::*;
+use std::path::Path;
+use std::process;
+
+use chip8remu::asm;
+
+const USAGE: &str = "usage: chip8asm <source.8o> [-o <out.ch8>] [--symbols <out.sym>]";
+
+fn fail(message: String) -> ! {
+    eprintln!("chip8asm: {}", message);
+    process::exit(1);
+}
+
+fn main() {
+    let mut source_path = None;
+    let mut out_path = None;
+    let mut symbols_path = None;
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-o" | "--output" => out_path = args.next(),
+            "--symbols" => symbols_path = args.next(),
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return;
+            },
+            _ if arg.starts_with('-') => {
+                eprintln!("chip8asm: unknown option {}\n{}", arg, USAGE);
+                process::exit(2);
+            },
+            _ => source_path = Some(arg),
+        }
+    }
+
+    let source_path = match source_path {
+        Some(path) => path,
+        None => {
+            eprintln!("{}", USAGE);
+            process::exit(2);
+        }
+    };
+    // Default to the source name with a .ch8 extension
+    let out_path = out_path.unwrap_or_else(|| {
+        Path::new(&source_path).with_extension("ch8").to_string_lossy().into_owned()
+    });
+
+    let mut source = String::new();
+    if let Err(e) = File::open(&source_path).and_then(|mut f| f.read_to_string(&mut source)) {
+        fail(format!("{}: {}", source_path, e));
+    }
+
+    let assembly = match asm::assemble(&source) {
+        Ok(assembly) => assembly,
+        Err(e) => fail(format!("{}: {}", source_path, e)),
+    };
+
+    if let Err(e) = File::create(&out_path).and_then(|mut f| f.write_all(&assembly.bytes)) {
+        fail(format!("{}: {}", out_path, e));
+    }
+
+    if let Some(symbols_path) = symbols_path {
+        if let Err(e) = File::create(&symbols_path).and_then(|mut f| assembly.write_symbols(&mut f)) {
+            fail(format!("{}: {}", symbols_path, e));
+        }
+    }
+}
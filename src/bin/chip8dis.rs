@@ -0,0 +1,56 @@
+/*  chip8dis.rs
+ *  Disassembles a CHIP-8 ROM into a labelled listing */
+
+extern crate chip8remu;
+
+use std::env;
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::process;
+
+use chip8remu::disasm::{Disassembly, Syntax};
+
+const USAGE: &str = "usage: chip8dis [--octo | --classic] <rom.ch8>";
+
+fn main() {
+    let mut syntax = Syntax::Classic;
+    let mut rom_path = None;
+
+    for arg in env::args().skip(1) {
+        match arg.as_str() {
+            "--octo" => syntax = Syntax::Octo,
+            "--classic" => syntax = Syntax::Classic,
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return;
+            },
+            _ if arg.starts_with('-') => {
+                eprintln!("chip8dis: unknown option {}\n{}", arg, USAGE);
+                process::exit(2);
+            },
+            _ => rom_path = Some(arg),
+        }
+    }
+
+    let rom_path = match rom_path {
+        Some(path) => path,
+        None => {
+            eprintln!("{}", USAGE);
+            process::exit(2);
+        }
+    };
+
+    let mut rom = Vec::new();
+    if let Err(e) = File::open(&rom_path).and_then(|mut f| f.read_to_end(&mut rom)) {
+        eprintln!("chip8dis: {}: {}", rom_path, e);
+        process::exit(1);
+    }
+
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    if let Err(e) = Disassembly::analyze(&rom).write_listing(&mut out, syntax) {
+        eprintln!("chip8dis: {}", e);
+        process::exit(1);
+    }
+}
@@ -1,19 +1,60 @@
 /*  chip8.rs
  *  Provides an implementation/interpreter of the chip8 system */
 
-use std::io;
-use std::io::Error;
-use std::io::prelude::*;
 use std::fs::File;
+use std::io::prelude::*;
+use std::path::Path;
+
+use rand;
+
+use debug::{Access, Breakpoints, MemoryAccess, Register, StopReason};
+use instruction::Instruction;
+use quirks::{IndexIncrement, Quirks};
+use error::{Chip8Error, FaultPolicy, RomError, StateError, StepOutcome};
+use state::{self, SaveState};
+use trace::{Registers, Tracer};
 
-use std::num::Wrapping;
+/// Width of the CHIP-8 display in pixels.
+pub const DISPLAY_WIDTH: usize = 64;
+/// Height of the CHIP-8 display in pixels.
+pub const DISPLAY_HEIGHT: usize = 32;
 
-extern crate rand;
-use rand::Rng;
+/// Size of the addressable memory in bytes.
+pub const MEMORY_SIZE: usize = 4096;
+/// Address programs are loaded at and start executing from.
+pub const PROGRAM_START: u16 = 0x200;
+/// Largest ROM that fits between `PROGRAM_START` and the end of memory.
+pub const MAX_ROM_SIZE: usize = MEMORY_SIZE - PROGRAM_START as usize;
+/// Rate the delay and sound timers count down at, and the frame rate `run_frame` assumes.
+pub const TIMER_HZ: u32 = 60;
+/// Default instructions per frame: 600 instructions per second.
+pub const DEFAULT_IPF: u32 = 10;
+
+/// Address the built-in hex font is installed at.
+pub const FONT_ADDR: u16 = 0x50;
+
+/// Built-in 4x5 hex digit sprites, 5 bytes per glyph for 0-F.
+pub const FONTSET: [u8; 80] = [
+    0xF0, 0x90, 0x90, 0x90, 0xF0, // 0
+    0x20, 0x60, 0x20, 0x20, 0x70, // 1
+    0xF0, 0x10, 0xF0, 0x80, 0xF0, // 2
+    0xF0, 0x10, 0xF0, 0x10, 0xF0, // 3
+    0x90, 0x90, 0xF0, 0x10, 0x10, // 4
+    0xF0, 0x80, 0xF0, 0x10, 0xF0, // 5
+    0xF0, 0x80, 0xF0, 0x90, 0xF0, // 6
+    0xF0, 0x10, 0x20, 0x40, 0x40, // 7
+    0xF0, 0x90, 0xF0, 0x90, 0xF0, // 8
+    0xF0, 0x90, 0xF0, 0x10, 0xF0, // 9
+    0xF0, 0x90, 0xF0, 0x90, 0x90, // A
+    0xE0, 0x90, 0xE0, 0x90, 0xE0, // B
+    0xF0, 0x80, 0x80, 0x80, 0xF0, // C
+    0xE0, 0x90, 0x90, 0x90, 0xE0, // D
+    0xF0, 0x80, 0xF0, 0x80, 0xF0, // E
+    0xF0, 0x80, 0xF0, 0x80, 0x80, // F
+];
 
 pub struct Chip8 {
-    pub fontset: [u8; 80],
-    pub memory: [u8; 4096],
+    pub memory: [u8; MEMORY_SIZE],
     pub v: [u8; 16],
     pub i: u16,
     pub dt: u8,
@@ -21,474 +62,1052 @@ pub struct Chip8 {
     pub pc: u16,
     pub sp: u8,
     pub stack: [u16; 16],
-    pub keyboard: [bool; 16],
-    //pub display: [[u8; 8]; 32],
-    pub display: [[bool; 64]; 32],
-    pub tinterval: i32,
-    pub tcount: i32,
+    keyboard: [bool; 16],
+    display: [[bool; DISPLAY_WIDTH]; DISPLAY_HEIGHT],
+    /// `FX0A` saw this key go down and is waiting for it to be released.
+    key_wait: Option<u8>,
+    /// Set while `FX0A` waits for a key to go down: the keys that were
+    /// already held when it started, ignored until they are released.
+    key_wait_held: Option<u16>,
+    /// Which platform's behavior the ambiguous instructions follow.
+    pub quirks: Quirks,
+    /// Set by `DXYN` under `Quirks::display_wait`; ends the current frame.
+    vblank_wait: bool,
+    /// What `emulate_cycle` does when an instruction faults.
+    pub fault_policy: FaultPolicy,
+    /// Set once a fault halts the machine under `FaultPolicy::Halt`.
+    halted: Option<Chip8Error>,
+    /// Instructions executed per 60 Hz frame by `run_frame`.
+    pub ipf: u32,
+    /// State of the `CXNN` random number generator (SplitMix64).
+    rng: u64,
+    /// Where executed instructions are logged, if anywhere.
+    trace: Option<Tracer>,
+    /// Instructions executed (or faulted on) since the last reset.
+    cycles: u64,
+    /// The loaded program, kept so `reset` can restore memory the program overwrote.
+    rom: Vec<u8>,
+    /// SHA-1 of `rom`, stamped on save states.
+    rom_hash: [u8; 20],
+    /// Where `run_until` stops.
+    pub breakpoints: Breakpoints,
+    /// Data memory touched by the last instruction executed.
+    last_access: Option<MemoryAccess>,
+    /// Breakpoint the next `run_until` may leave without stopping.
+    skip_breakpoint: Option<u16>,
 }
 
 impl Default for Chip8 {
     fn default() -> Chip8 {
+        let mut memory = [0u8; MEMORY_SIZE];
+        let font = FONT_ADDR as usize;
+        memory[font..font + FONTSET.len()].copy_from_slice(&FONTSET);
+
         Chip8{
-            fontset: [0xF0, 0x90, 0x90, 0x90, 0xF0, // 0
-            0x20, 0x60, 0x20, 0x20, 0x70, // 1 
-            0xF0, 0x10, 0xF0, 0x80, 0xF0, // 2
-            0xF0, 0x10, 0xF0, 0x10, 0xF0, // 3
-            0x90, 0x90, 0xF0, 0x10, 0x10, // 4
-            0xF0, 0x80, 0xF0, 0x10, 0xF0, // 5
-            0xF0, 0x80, 0xF0, 0x90, 0xF0, // 6
-            0xF0, 0x10, 0x20, 0x40, 0x40, // 7
-            0xF0, 0x90, 0xF0, 0x90, 0xF0, // 8
-            0xF0, 0x90, 0xF0, 0x10, 0xF0, // 9
-            0xF0, 0x90, 0xF0, 0x90, 0x90, // A
-            0xE0, 0x90, 0xE0, 0x90, 0xE0, // B
-            0xF0, 0x80, 0x80, 0x80, 0xF0, // C
-            0xE0, 0x90, 0x90, 0x90, 0xE0, // D
-            0xF0, 0x80, 0xF0, 0x80, 0xF0, // E
-            0xF0, 0x80, 0xF0, 0x80, 0x80], // F
-            memory: [0u8; 4096],
+            memory,
             v: [0u8; 16],
             i: 0u16,
             dt: 0u8,
             st: 0u8,
-            pc: 0u16,
+            pc: PROGRAM_START,
             sp: 0u8,
             stack: [0u16; 16],
             keyboard: [false; 16],
-            display: [[false; 64]; 32],
-            tinterval: 10,
-            tcount: 0
+            display: [[false; DISPLAY_WIDTH]; DISPLAY_HEIGHT],
+            key_wait: None,
+            key_wait_held: None,
+            quirks: Quirks::default(),
+            vblank_wait: false,
+            fault_policy: FaultPolicy::Halt,
+            halted: None,
+            ipf: DEFAULT_IPF,
+            rng: rand::random(),
+            trace: None,
+            cycles: 0,
+            rom: Vec::new(),
+            rom_hash: state::rom_hash(&[]),
+            breakpoints: Breakpoints::default(),
+            last_access: None,
+            skip_breakpoint: None,
         }
     }
 }
 
 impl Chip8 {
-    /** Loads a (static) file into memory, at least for now */
-    pub fn load_rom(&mut self) -> Result<(), Error> {
+    /** Creates a powered-on machine with the font installed and empty program memory */
+    pub fn new() -> Chip8 {
+        Chip8{..Default::default()}
+    }
 
-        // Load ROM
-        let mut f = try!(File::open("roms/programs/Chip8 emulator Logo [Garstyciuks].ch8"));
-        let mut i = 0x50;
+    /** Creates a machine that follows the given quirks profile */
+    pub fn with_quirks(quirks: Quirks) -> Chip8 {
+        Chip8{quirks, ..Default::default()}
+    }
+
+    /** Executes a single instruction, ignoring breakpoints */
+    pub fn step(&mut self) -> Result<StepOutcome, Chip8Error> {
+        self.skip_breakpoint = None;
+        self.emulate_cycle()
+    }
 
-        // Load font
-        for byte in self.fontset.bytes() {
-            self.memory[i] = byte.unwrap() as u8;
-            i += 1;
+    /** Runs one 60 Hz frame: up to `ipf` instructions followed by one timer
+     *  tick. The frame ends early if an instruction waits for the vertical
+     *  blank, or stops without ticking the timers if an instruction traps. */
+    pub fn run_frame(&mut self) -> Result<StepOutcome, Chip8Error> {
+        self.vblank_wait = false;
+        for _ in 0..self.ipf {
+            let outcome = self.step()?;
+            if let StepOutcome::Trapped(_) = outcome {
+                return Ok(outcome);
+            }
+            if self.vblank_wait {
+                break;
+            }
         }
-        println!("Font loaded, ended at {:X}", i);
+        self.tick_timers();
+        Ok(StepOutcome::Executed)
+    }
+
+    /** Executes up to `limit` instructions, stopping early at a breakpoint,
+     *  watchpoint, fault or vertical blank wait. A breakpoint stops before its
+     *  instruction runs; calling again from there runs it. Timers are not
+     *  ticked. */
+    pub fn run_until(&mut self, limit: u32) -> StopReason {
+        self.vblank_wait = false;
+        let watch_registers = self.breakpoints.registers().next().is_some();
+        for _ in 0..limit {
+            let pc = self.pc;
+            if self.breakpoints.contains(pc) && self.skip_breakpoint != Some(pc) {
+                self.skip_breakpoint = Some(pc);
+                return StopReason::Breakpoint(pc);
+            }
+
+            let before = if watch_registers { Some((self.v, self.i, self.dt, self.st)) } else { None };
+            match self.step() {
+                Ok(StepOutcome::Executed) | Ok(StepOutcome::Skipped(_)) => {},
+                Ok(StepOutcome::Trapped(e)) => return StopReason::Trapped(e),
+                Err(e) => return StopReason::Halted(e),
+            }
+
+            if let Some(access) = self.last_access {
+                if let Some(addr) = self.breakpoints.memory_hit(&access) {
+                    return StopReason::Memory { pc, addr, access: access.access };
+                }
+            }
+            if let Some((v, i, dt, st)) = before {
+                let old = |register| match register {
+                    Register::V(x) => v[x as usize & 0xF] as u16,
+                    Register::I => i,
+                    Register::Dt => dt as u16,
+                    Register::St => st as u16,
+                };
+                let changed = self.breakpoints.registers()
+                    .find(|&register| old(register) != self.register(register));
+                if let Some(register) = changed {
+                    return StopReason::Register { pc, register, old: old(register), new: self.register(register) };
+                }
+            }
+            if self.vblank_wait {
+                return StopReason::VBlank;
+            }
+        }
+        StopReason::Limit
+    }
+
+    /** Lets the next `run_until` execute the instruction at PC even if it has
+     *  a breakpoint, as a debugger does when resuming from wherever it stopped */
+    pub fn skip_breakpoint(&mut self) {
+        self.skip_breakpoint = Some(self.pc);
+    }
+
+    /** Current value of a register */
+    pub fn register(&self, register: Register) -> u16 {
+        match register {
+            Register::V(x) => self.v[x as usize & 0xF] as u16,
+            Register::I => self.i,
+            Register::Dt => self.dt as u16,
+            Register::St => self.st as u16,
+        }
+    }
 
-        i = 0x200;
-        for byte in f.bytes() {
-            //print!("{}", byte.unwrap() as char);
-            self.memory[i] = byte.unwrap() as u8;
-            i += 1;
+    /** Restarts the `CXNN` random number sequence from `seed`, making runs reproducible */
+    pub fn seed_rng(&mut self, seed: u64) {
+        self.rng = seed;
+    }
+
+    /** Logs executed instructions with `tracer`, or stops logging if `None` */
+    pub fn set_trace(&mut self, tracer: Option<Tracer>) {
+        self.trace = tracer;
+    }
+
+    /** Instructions executed since the machine was reset or its ROM loaded,
+     *  counting those that faulted. Not part of save states. */
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    /** Counts the delay and sound timers down by one. Call at `TIMER_HZ` when
+     *  driving the machine with `step` instead of `run_frame`. */
+    pub fn tick_timers(&mut self) {
+        self.dt = self.dt.saturating_sub(1);
+        self.st = self.st.saturating_sub(1);
+    }
+
+    /** The fault that halted the machine, if any */
+    pub fn halted(&self) -> Option<&Chip8Error> {
+        self.halted.as_ref()
+    }
+
+    /** Marks keypad key `key` (0x0-0xF) as pressed or released */
+    pub fn set_key(&mut self, key: u8, pressed: bool) {
+        self.keyboard[(key & 0xF) as usize] = pressed;
+    }
+
+    /** Returns whether keypad key `key` (0x0-0xF) is currently held */
+    pub fn is_key_down(&self, key: u8) -> bool {
+        self.keyboard[(key & 0xF) as usize]
+    }
+
+    /** Whether the beeper should be sounding, i.e. the sound timer is running */
+    pub fn sound_active(&self) -> bool {
+        self.st > 0
+    }
+
+    /** Read-only view of the display, indexed as `[y][x]` */
+    pub fn framebuffer(&self) -> &[[bool; DISPLAY_WIDTH]; DISPLAY_HEIGHT] {
+        &self.display
+    }
+
+    /** Reads the file at `path` and loads it as the program (see `load_rom_bytes`) */
+    pub fn load_rom_from_path<P: AsRef<Path>>(&mut self, path: P) -> Result<(), RomError> {
+        let mut rom = Vec::new();
+        File::open(path)?.read_to_end(&mut rom)?;
+        self.load_rom_bytes(&rom)
+    }
+
+    /** Replaces the program with `rom` and resets the machine to run it from
+     *  `PROGRAM_START` */
+    pub fn load_rom_bytes(&mut self, rom: &[u8]) -> Result<(), RomError> {
+        if rom.len() > MAX_ROM_SIZE {
+            return Err(RomError::TooLarge(rom.len()));
         }
-        println!("ROM Loaded");
 
-        self.pc = 0x200u16;
+        self.rom = rom.to_vec();
+        self.rom_hash = state::rom_hash(rom);
+        self.reset();
 
         Ok(())
     }
 
-    pub fn dbg(&mut self) {
-        print!("v0: {:08b}, v1: {:08b}, v2: {:08b}, v3: {:08b}\n", self.v[0], self.v[1], self.v[2], self.v[3]);
-        print!("v4: {:08b}, v5: {:08b}, v6: {:08b}, v7: {:08b}\n", self.v[4], self.v[5], self.v[6], self.v[7]);
-        print!("v8: {:08b}, v9: {:08b}, vA: {:08b}, vB: {:08b}\n", self.v[8], self.v[9], self.v[10], self.v[11]);
-        print!("vC: {:08b}, vD: {:08b}, vE: {:08b}, vF: {:08b}\n", self.v[12], self.v[13], self.v[14], self.v[15]);
-        print!("i: {:016b}, dt: {:08b}, st: {:08b}, pc: {:04X}\n", self.i, self.dt, self.st, self.pc);
+    /** The program as it was loaded, before it ran */
+    pub fn rom(&self) -> &[u8] {
+        &self.rom
     }
 
-    /** Runs a cycle on the chip8 */
-    pub fn emulate_cycle(&mut self) {
-        let mut x = 0u8;
-        let mut y = 0u8;
-        let mut height = 0u8;
-        let mut pixel = 0u8;
+    /** Captures everything that determines what the machine does next */
+    pub fn save_state(&self) -> SaveState {
+        SaveState {
+            rom_hash: self.rom_hash,
+            memory: self.memory,
+            v: self.v,
+            i: self.i,
+            dt: self.dt,
+            st: self.st,
+            pc: self.pc,
+            sp: self.sp,
+            stack: self.stack,
+            keyboard: self.keyboard,
+            display: self.display,
+            key_wait: self.key_wait,
+            key_wait_held: self.key_wait_held,
+            vblank_wait: self.vblank_wait,
+            quirks: self.quirks,
+            rng: self.rng,
+        }
+    }
+
+    /** Restores a state captured by `save_state`. Fails without touching the
+     *  machine if the state was saved from a different ROM. Clears any halt. */
+    pub fn load_state(&mut self, state: &SaveState) -> Result<(), StateError> {
+        if state.rom_hash != self.rom_hash {
+            return Err(StateError::RomMismatch);
+        }
+
+        self.memory = state.memory;
+        self.v = state.v;
+        self.i = state.i;
+        self.dt = state.dt;
+        self.st = state.st;
+        self.pc = state.pc;
+        self.sp = state.sp;
+        self.stack = state.stack;
+        self.keyboard = state.keyboard;
+        self.display = state.display;
+        self.key_wait = state.key_wait;
+        self.key_wait_held = state.key_wait_held;
+        self.vblank_wait = state.vblank_wait;
+        self.quirks = state.quirks;
+        self.rng = state.rng;
+        self.halted = None;
+        Ok(())
+    }
+
+    /** Soft reset: clears registers, stack, timers, display, keypad and any
+     *  halt, and restores memory to the freshly loaded ROM. Quirks, `ipf`, the
+     *  fault policy and the random number generator are left alone; frontends
+     *  re-press any keys still held. */
+    pub fn reset(&mut self) {
+        self.memory = [0u8; MEMORY_SIZE];
+        let font = FONT_ADDR as usize;
+        self.memory[font..font + FONTSET.len()].copy_from_slice(&FONTSET);
+        let start = PROGRAM_START as usize;
+        self.memory[start..start + self.rom.len()].copy_from_slice(&self.rom);
+
+        self.v = [0u8; 16];
+        self.i = 0;
+        self.dt = 0;
+        self.st = 0;
+        self.pc = PROGRAM_START;
+        self.sp = 0;
+        self.stack = [0u16; 16];
+        self.display = [[false; DISPLAY_WIDTH]; DISPLAY_HEIGHT];
+        self.keyboard = [false; 16];
+        self.key_wait = None;
+        self.key_wait_held = None;
+        self.vblank_wait = false;
+        self.halted = None;
+        self.skip_breakpoint = None;
+        self.cycles = 0;
+    }
+
+    /** Runs a cycle on the chip8, applying `fault_policy` if the instruction faults */
+    pub fn emulate_cycle(&mut self) -> Result<StepOutcome, Chip8Error> {
+        if let Some(ref e) = self.halted {
+            return Err(e.clone());
+        }
+
+        // Only capture what the trace needs when there is a tracer and it
+        // wants this instruction
+        let traced = match self.trace {
+            Some(ref tracer) => self.fetch(self.pc)
+                .filter(|&opcode| tracer.wants(self.pc, opcode))
+                .map(|opcode| (self.pc, opcode, self.registers())),
+            None => None,
+        };
+
+        let result = match self.execute() {
+            Ok(()) => Ok(StepOutcome::Executed),
+            Err(e) => match self.fault_policy {
+                FaultPolicy::Halt => {
+                    self.halted = Some(e.clone());
+                    Err(e)
+                },
+                FaultPolicy::Nop => {
+                    self.pc = self.pc.wrapping_add(2);
+                    Ok(StepOutcome::Skipped(e))
+                },
+                FaultPolicy::Trap => Ok(StepOutcome::Trapped(e)),
+            }
+        };
+        self.cycles += 1;
+
+        if let Some((pc, opcode, before)) = traced {
+            let after = self.registers();
+            let fault = match result {
+                Ok(StepOutcome::Executed) => None,
+                Ok(StepOutcome::Skipped(ref e)) | Ok(StepOutcome::Trapped(ref e)) | Err(ref e) => Some(e),
+            };
+            if let Some(ref mut tracer) = self.trace {
+                tracer.record(self.cycles, pc, opcode, &before, &after, fault);
+            }
+        }
+        result
+    }
+
+    /** The registers an instruction can change, for the trace */
+    fn registers(&self) -> Registers {
+        Registers { v: self.v, i: self.i, dt: self.dt, st: self.st, sp: self.sp }
+    }
+
+    /** Next byte from the SplitMix64 generator. Kept in-core rather than using
+     *  `rand` so a seeded run is reproducible on every platform. */
+    fn next_random(&mut self) -> u8 {
+        self.rng = self.rng.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.rng;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        (z ^ (z >> 31)) as u8
+    }
+
+    /** Checks that `len` bytes starting at `addr` lie inside memory */
+    fn check_mem(&self, addr: u16, len: usize) -> Result<usize, Chip8Error> {
+        let start = addr as usize;
+        if start + len > MEMORY_SIZE {
+            return Err(Chip8Error::MemoryOutOfBounds { pc: self.pc, addr: start + len - 1 });
+        }
+        Ok(start)
+    }
+
+    /** Records that the current instruction accesses `len` bytes at I, for watchpoints */
+    fn touch(&mut self, access: Access, len: usize) {
+        self.last_access = Some(MemoryAccess { addr: self.i, len: len as u16, access });
+    }
+
+    /** Moves I past the registers transferred by `FX55`/`FX65`, per the load/store quirk */
+    fn advance_index(&mut self, x: u16) {
+        match self.quirks.load_store {
+            IndexIncrement::XPlusOne => self.i += x + 1,
+            IndexIncrement::X => self.i += x,
+            IndexIncrement::None => {},
+        }
+    }
+
+    /** Reads the big-endian instruction word at `addr`, if it lies inside memory */
+    pub fn fetch(&self, addr: u16) -> Option<u16> {
+        let addr = addr as usize;
+        if addr + 1 < MEMORY_SIZE {
+            Some((self.memory[addr] as u16) << 8 | self.memory[addr + 1] as u16)
+        } else {
+            None
+        }
+    }
+
+    /** Fetches and executes one instruction. On error, no machine state has been
+     *  modified, so the instruction can be skipped or retried. */
+    fn execute(&mut self) -> Result<(), Chip8Error> {
+        self.last_access = None;
 
         //Fetch
-        print!("op: {:02X}{:02X}\t", self.memory[self.pc as usize] as u16, self.memory[self.pc as usize + 1] as u16);
-        let opcode = (self.memory[self.pc as usize] as u16) << 8 | (self.memory[self.pc as usize + 1] as u16);
-        //Decode/Execute
-        
-        /* opcodes:
-        1NNN 2NNN 3XNN 4XNN 5XY0 6XNN 7XNN 8XY0 8XY1 8XY2 8XY3 8XY4 8XY5 8XY6 8XY7 8XYE 9XY0 ANNN BNNN
-        CXNN SXYN EX9E EXA1 FX07 FX0A FX15 FX18 FX1E FX29 FX33 FX55 FX64 */
-
-
-        let mut addr = 0u16;
-
-        match opcode & 0xF000 {
-            0x0000 => {
-                match opcode & 0xFFFF {
-                    0x00E0 => {
-                        //Clear display
-                        println!("00E0");
-                        self.display = [[false; 64]; 32];
-                        //self.display = [[0u8; 8]; 32];
-                        self.pc += 2;
-                    },
-                    0x00EE => {
-                        //Return from a subroutine
-                        println!("00EE");
-                        self.pc = self.stack[self.sp as usize];
-                        self.sp -= 1;
-                    },
-                    _ => { //0NNN
-                        //Ignore this, not needed
-                        println!("0NNN");
-                        self.pc += 2;
-                    }
+        let opcode = match self.fetch(self.pc) {
+            Some(opcode) => opcode,
+            None => return Err(Chip8Error::MemoryOutOfBounds { pc: self.pc, addr: self.pc as usize + 1 }),
+        };
+
+        //Decode
+        let instr = match Instruction::decode(opcode) {
+            Some(instr) => instr,
+            None => return Err(Chip8Error::InvalidOpcode { pc: self.pc, opcode }),
+        };
+
+        //Execute
+        let mut next_pc = self.pc + 2;
+
+        match instr {
+            Instruction::Sys(_) => {
+                //Ignore this, not needed
+            },
+            Instruction::Cls => {
+                self.display = [[false; DISPLAY_WIDTH]; DISPLAY_HEIGHT];
+            },
+            Instruction::Ret => {
+                if self.sp == 0 {
+                    return Err(Chip8Error::StackUnderflow { pc: self.pc });
                 }
+                self.sp -= 1;
+                next_pc = self.stack[self.sp as usize];
             },
-            0x1000 => {
-                //Jump
-                println!("1NNN");
-                self.pc = opcode & 0x0FFF;
+            Instruction::Jp(nnn) => {
+                next_pc = nnn;
             },
-            0x2000 => {
-                //Call subroutine
-                println!("2NNN");
+            Instruction::Call(nnn) => {
+                if self.sp as usize >= self.stack.len() {
+                    return Err(Chip8Error::StackOverflow { pc: self.pc });
+                }
+                self.stack[self.sp as usize] = next_pc;
                 self.sp += 1;
-                self.stack[self.sp as usize] = self.pc;
-                self.pc = opcode & 0x0FFF;
-            },
-            0x3000 => {
-                //Skip next instruction if VX = NN
-                println!("3XNN");
-                if self.v[((opcode & 0x0F00) >> 8) as usize] == (opcode & 0x00FF) as u8 {
-                    self.pc += 4;
-                } else {
-                    self.pc += 2;
+                next_pc = nnn;
+            },
+            Instruction::SeVxByte { x, nn } => {
+                if self.v[x as usize] == nn {
+                    next_pc += 2;
                 }
             },
-            0x4000 => {
-                //Skip next instruction if VX != NN
-                println!("4XNN");
-                if self.v[((opcode & 0x0F00) >> 8) as usize] != (opcode & 0x00FF) as u8 {
-                    self.pc += 4;
-                } else {
-                    self.pc += 2;
+            Instruction::SneVxByte { x, nn } => {
+                if self.v[x as usize] != nn {
+                    next_pc += 2;
                 }
             },
-            0x5000 => {
-                match opcode & 0x000F {
-                    0x0000 => {
-                        // Skip next instruction if VX = VY.
-                        println!("5XY0");
-                        let vx = self.v[((opcode & 0x0F00) >> 8) as usize];
-                        let vy = self.v[((opcode & 0x00F0) >> 4) as usize];
-                        if vx == vy {
-                            self.pc += 4;
-                        } else {
-                            self.pc += 2;
-                        }
-                    },
-                    _ => {
-                        println!("Invalid opcode: {:X}", opcode);
-                    }
+            Instruction::SeVxVy { x, y } => {
+                if self.v[x as usize] == self.v[y as usize] {
+                    next_pc += 2;
                 }
             },
-            0x6000 => {
-                //Set VX = NN
-                println!("6XNN");
-                let x = (opcode & 0x0F00) >> 8;
-                let n = opcode & 0x00FF;
-                self.v[x as usize] = n as u8;
-                self.pc += 2;
-            },
-            0x7000 => {
-                //Set VX = VX + NN.
-                println!("7XNN");
-                let x = (opcode & 0x0F00) >> 8;
-                let n = (opcode & 0x00FF) as u8;
-                self.v[x as usize] = self.v[x as usize].wrapping_add(n);
-                self.pc += 2;
-            },
-            0x8000 => {
-                match opcode & 0x000F {
-                    0x0000 => {
-                        println!("8XY0");
-                        //Set VX = VY.
-                        let x = (opcode & 0x0F00) >> 8;
-                        let y = (opcode & 0x00F0) >> 4;
-                        self.v[x as usize] = self.v[y as usize];
-                        self.pc += 2;
-                    },
-                    0x0001 => {
-                        println!("8XY1");
-                        //Set VX OR VY.
-                        let x = (opcode & 0x0F00) >> 8;
-                        let y = (opcode & 0x00F0) >> 4;
-                        self.v[x as usize] = self.v[x as usize] | self.v[y as usize];
-                        self.pc += 2;
-                    },
-                    0x0002 => {
-                        println!("8XY2");
-                        //Set VX OR VY.
-                        let x = (opcode & 0x0F00) >> 8;
-                        let y = (opcode & 0x00F0) >> 4;
-                        self.v[x as usize] = self.v[x as usize] & self.v[y as usize];
-                        self.pc += 2;
-                    },
-                    0x0003 => {
-                        println!("8XY3");
-                        //Set VX OR VY.
-                        let x = (opcode & 0x0F00) >> 8;
-                        let y = (opcode & 0x00F0) >> 4;
-                        self.v[x as usize] = self.v[x as usize] ^ self.v[y as usize];
-                        self.pc += 2;
-                    },
-                    0x0004 => {
-                        println!("8XY4");
-                        //Add VX, VY, set VF=carry.
-                        let x = (opcode & 0x0F00) >> 8;
-                        let y = (opcode & 0x00F0) >> 4;
-                        let addition: u16 = (self.v[x as usize] as u16).wrapping_add(self.v[y as usize] as u16);
-                        self.v[x as usize] = addition as u8;
-                        self.v[15] = (addition & 0x0100 >> 8) as u8;
-                        self.pc += 2;
-                    },
-                    0x0005 => {
-                        //Sub VX, VY, set VF=(VX > VY)
-                        println!("8XY5");
-                        let x = (opcode & 0x0F00) >> 8;
-                        let y = (opcode & 0x00F0) >> 4;
-                        if self.v[x as usize] > self.v[y as usize] {
-                            self.v[15] = 1;
-                        } else {
-                            self.v[15] = 0;
+            Instruction::LdVxByte { x, nn } => {
+                self.v[x as usize] = nn;
+            },
+            Instruction::AddVxByte { x, nn } => {
+                self.v[x as usize] = self.v[x as usize].wrapping_add(nn);
+            },
+            Instruction::LdVxVy { x, y } => {
+                self.v[x as usize] = self.v[y as usize];
+            },
+            Instruction::Or { x, y } => {
+                self.v[x as usize] |= self.v[y as usize];
+                if self.quirks.vf_reset {
+                    self.v[15] = 0;
+                }
+            },
+            Instruction::And { x, y } => {
+                self.v[x as usize] &= self.v[y as usize];
+                if self.quirks.vf_reset {
+                    self.v[15] = 0;
+                }
+            },
+            Instruction::Xor { x, y } => {
+                self.v[x as usize] ^= self.v[y as usize];
+                if self.quirks.vf_reset {
+                    self.v[15] = 0;
+                }
+            },
+            Instruction::AddVxVy { x, y } => {
+                // VF is written last so it holds the flag even when X or Y is F
+                let (sum, carry) = self.v[x as usize].overflowing_add(self.v[y as usize]);
+                self.v[x as usize] = sum;
+                self.v[15] = carry as u8;
+            },
+            Instruction::Sub { x, y } => {
+                let (diff, borrow) = self.v[x as usize].overflowing_sub(self.v[y as usize]);
+                self.v[x as usize] = diff;
+                self.v[15] = !borrow as u8;
+            },
+            Instruction::Shr { x, y } => {
+                let vx = self.v[if self.quirks.shift_vx { x } else { y } as usize];
+                self.v[x as usize] = vx >> 1;
+                self.v[15] = vx & 1;
+            },
+            Instruction::Subn { x, y } => {
+                let (diff, borrow) = self.v[y as usize].overflowing_sub(self.v[x as usize]);
+                self.v[x as usize] = diff;
+                self.v[15] = !borrow as u8;
+            },
+            Instruction::Shl { x, y } => {
+                let vx = self.v[if self.quirks.shift_vx { x } else { y } as usize];
+                self.v[x as usize] = vx << 1;
+                self.v[15] = vx >> 7;
+            },
+            Instruction::SneVxVy { x, y } => {
+                if self.v[x as usize] != self.v[y as usize] {
+                    next_pc += 2;
+                }
+            },
+            Instruction::LdI(nnn) => {
+                self.i = nnn;
+            },
+            Instruction::JpV0(nnn) => {
+                let x = if self.quirks.jump_with_vx { (nnn >> 8) as usize } else { 0 };
+                next_pc = nnn + self.v[x] as u16;
+            },
+            Instruction::Rnd { x, nn } => {
+                self.v[x as usize] = nn & self.next_random();
+            },
+            Instruction::Drw { x, y, n } => {
+                // Sprites are 8 pixels wide, up to 15 pixels tall, XORed onto the
+                // display. VF=1 if any lit pixel was turned off.
+                // The starting position always wraps; the rest of the sprite
+                // clips at the edges unless the wrap quirk is set.
+                let address = self.check_mem(self.i, n as usize)?;
+                self.touch(Access::Read, n as usize);
+                let vx = self.v[x as usize] as usize % DISPLAY_WIDTH;
+                let vy = self.v[y as usize] as usize % DISPLAY_HEIGHT;
+                let wrap = self.quirks.wrap_sprites;
+
+                self.v[15] = 0;
+                for row in 0..n as usize {
+                    let mut py = vy + row;
+                    if py >= DISPLAY_HEIGHT {
+                        if !wrap {
+                            break;
                         }
-                        self.v[x as usize] = self.v[x as usize].wrapping_sub(self.v[y as usize]);
-                        self.pc += 2;
-                    },
-                    0x0006 => {
-                        // Vx >>= 1, VF=LSB
-                        println!("8XY6");
-                        let x = (opcode & 0x0F00) >> 8;
-                        let vx = self.v[x as usize];
-                        self.v[15] = vx & 1;
-
-                        self.v[x as usize] = vx >> 1;
-                        self.pc += 2;
-                    },
-                    0x0007 => {
-                        // VX = VY - VX, set VF=(VY > VX)
-                        println!("8XY7");
-                        let x = (opcode & 0x0F00) >> 8;
-                        let y = (opcode & 0x00F0) >> 4;
-                        if self.v[y as usize] > self.v[x as usize] {
-                            self.v[15] = 1u8;
-                        } else {
-                            self.v[15] = 0u8;
+                        py %= DISPLAY_HEIGHT;
+                    }
+                    let sprite = self.memory[address + row];
+                    for col in 0..8 {
+                        let mut px = vx + col;
+                        if px >= DISPLAY_WIDTH {
+                            if !wrap {
+                                break;
+                            }
+                            px %= DISPLAY_WIDTH;
+                        }
+                        if (sprite >> (7 - col)) & 1 == 1 {
+                            if self.display[py][px] {
+                                self.v[15] = 1;
+                            }
+                            self.display[py][px] ^= true;
                         }
-                        self.v[y as usize] = self.v[y as usize].wrapping_sub(self.v[x as usize]);
-                        self.pc += 2;
-                    },
-                    0x000E => {
-                        // VX <<= 1, VF=MSB
-                        println!("8XYE");
-                        let x = (opcode & 0x0F00) >> 8;
-                        let vx = self.v[x as usize];
-                        self.v[15] = vx & 1;
-
-                        self.v[x as usize] = vx << 1;
-                        self.pc += 2;
-                    },
-                    _ => {
-                        println!("Invalid opcode: {:X}", opcode);
                     }
                 }
+
+                if self.quirks.display_wait {
+                    self.vblank_wait = true;
+                }
+            },
+            Instruction::Skp { x } => {
+                if self.keyboard[(self.v[x as usize] & 0xF) as usize] {
+                    next_pc += 2;
+                }
+            },
+            Instruction::Sknp { x } => {
+                if !self.keyboard[(self.v[x as usize] & 0xF) as usize] {
+                    next_pc += 2;
+                }
             },
-            0x9000 => {
-                match opcode & 0x000F {
-                    0x0000 => {
-                        // Skip next instruction if Vx != Vy
-                        println!("9XY0");
-                        let x = (opcode & 0x0F00) >> 8;
-                        let y = (opcode & 0x00F0) >> 4;
-                        if self.v[x as usize] != self.v[y as usize] {
-                            self.pc += 4;
-                        } else {
-                            self.pc += 2;
+            Instruction::LdVxDt { x } => {
+                self.v[x as usize] = self.dt;
+            },
+            Instruction::LdVxK { x } => {
+                // As on the VIP, the key is reported once it has been pressed
+                // and released; until then this instruction repeats. Keys
+                // already held when it started only count once released and
+                // pressed again.
+                next_pc = self.pc;
+                match self.key_wait {
+                    None => {
+                        let down = self.keyboard.iter().enumerate()
+                            .fold(0u16, |bits, (key, &down)| bits | (down as u16) << key);
+                        let held = self.key_wait_held.unwrap_or(down) & down;
+                        match (0..16).find(|&key| (down & !held) & (1 << key) != 0) {
+                            Some(key) => {
+                                self.key_wait = Some(key as u8);
+                                self.key_wait_held = None;
+                            },
+                            None => self.key_wait_held = Some(held),
+                        }
+                    },
+                    Some(key) => {
+                        if !self.keyboard[key as usize] {
+                            self.v[x as usize] = key;
+                            self.key_wait = None;
+                            next_pc = self.pc + 2;
                         }
                     },
-                    _ => {
-                        println!("Invalid opcode: {:X}", opcode);
-                    }
                 }
-
             },
-            0xA000 => {
-                // Set I = NNN
-                println!("ANNN");
-                self.i = opcode & 0x0FFF;
-                self.pc += 2;
-            },
-            0xB000 => {
-                // Jump to NNN + V0
-                println!("BNNN");
-                self.pc = (opcode & 0x0FFF) + (self.v[0] as u16);
-            },
-            0xC000 => {
-                //Set VX = random byte AND NN
-                println!("CXNN");
-                let x = (opcode & 0x0F00) >> 8;
-                let n = opcode as u8;
-                let mut rng = rand::thread_rng();
-                self.v[x as usize] = n & rng.gen::<u8>();
-                self.pc += 2;
-            },
-            0xD000 => {
-                // Display sprite starting at coordinates (Vx, Vy) at memory location I byte-length n, VF=1 if any active pixel overwritten
-                // Sprites are always 8 pixels wide, up to 15 pixels tall
-                // Sprites are XORed onto the display
-                println!("DXYN");
-                let x = (opcode & 0x0F00) >> 8;
-                let y = (opcode & 0x00F0) >> 4;
-                let n = (opcode & 0x000F) as u8;
-                let mut i = 0u8;
-                let mut j = 0u8;
-                let mut newbit:bool = false;
-                let mut oldbit:bool = false;
-
+            Instruction::LdDtVx { x } => {
+                self.dt = self.v[x as usize];
+            },
+            Instruction::LdStVx { x } => {
+                self.st = self.v[x as usize];
+            },
+            Instruction::AddIVx { x } => {
+                self.i = self.i.wrapping_add(self.v[x as usize] as u16);
+            },
+            Instruction::LdFVx { x } => {
+                self.i = FONT_ADDR + 5 * (self.v[x as usize] & 0xF) as u16;
+            },
+            Instruction::LdBVx { x } => {
                 let vx = self.v[x as usize];
-                let vy = self.v[y as usize];
+                let addr = self.check_mem(self.i, 3)?;
+                self.touch(Access::Write, 3);
 
-                let address = self.i;
+                self.memory[addr] = vx / 100; //hundreds place
+                self.memory[addr + 1] = (vx / 10) % 10; //tens place
+                self.memory[addr + 2] = vx % 10; //ones place
+            },
+            Instruction::LdIVx { x } => {
+                let x = x as usize;
+                let addr = self.check_mem(self.i, x + 1)?;
+                self.touch(Access::Write, x + 1);
 
-                
-                //print!("D {} {} {}, ", x, y, n);
-                //print!("Vx={}, Vy={}, address={:X} : {:X}\n", self.v[x as usize], self.v[y as usize], address, self.memory[address as usize]);
+                self.memory[addr..addr + x + 1].copy_from_slice(&self.v[..x + 1]);
 
-                while i < n {
-                    j = 0;
-                    while j < 8 {
-                        newbit = (self.memory[(address + i as u16) as usize] >> (7 - j)) & 1 == 1;
-                        if newbit && vx + j < 64 {
-                            if self.display[(vy + i) as usize][(vx + j) as usize] {
-                                self.v[15] = 1;
-                            }
-                            self.display[(vy + i) as usize][(vx + j) as usize] ^= newbit;
-                        }
-                        j += 1;
-                    }
+                self.advance_index(x as u16);
+            },
+            Instruction::LdVxI { x } => {
+                let x = x as usize;
+                let addr = self.check_mem(self.i, x + 1)?;
+                self.touch(Access::Read, x + 1);
 
-                    i += 1;
-                }
+                self.v[..x + 1].copy_from_slice(&self.memory[addr..addr + x + 1]);
 
-                self.pc += 2;
+                self.advance_index(x as u16);
             },
-            0xE000 => {
-                match opcode & 0x00FF {
-                    0x009E => {
-                        println!("TODO EX9E");
-                        self.pc += 2;
-                        //TODO
-                    },
-                    0x00A1 => {
-                        println!("TODO EXA1");
-                        self.pc += 2;
-                        //TODO
-                    },
-                    _ => {
-                        println!("Invalid opcode: {:X}", opcode);
-                        //TODO
-                    }
-                }
+        }
+
+        self.pc = next_pc;
+
+        Ok(())
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use debug::Watch;
+
+    /** A machine with `quirks` running the instruction words `program` */
+    fn machine(quirks: Quirks, program: &[u16]) -> Chip8 {
+        let rom: Vec<u8> = program.iter().flat_map(|word| word.to_be_bytes().to_vec()).collect();
+        let mut core = Chip8::with_quirks(quirks);
+        core.load_rom_bytes(&rom).unwrap();
+        core
+    }
+
+    /** Runs `setup` instructions of `program`, then checks that the next one
+     *  raises `fault` and that each fault policy handles it */
+    fn check_fault(program: &[u16], setup: usize, fault: Chip8Error) {
+        for &policy in [FaultPolicy::Halt, FaultPolicy::Nop, FaultPolicy::Trap].iter() {
+            let mut core = machine(Quirks::default(), program);
+            core.fault_policy = policy;
+            for _ in 0..setup {
+                assert_eq!(core.step(), Ok(StepOutcome::Executed));
             }
-            0xF000 => {
-                match opcode & 0x00FF {
-                    0x0007 => {
-                        println!("FX07");
-                        self.v[((opcode & 0x0F00) >> 8) as usize] = self.dt;
-                        self.pc += 2;
-                    },
-                    0x000A => {
-                        println!("TODO FX0A");
-                        self.pc += 2;
-                        //TODO
-                    },
-                    0x0015 => {
-                        println!("FX15");
-                        self.dt = self.v[((opcode & 0x0F00) >> 8) as usize];
-                        self.pc += 2;
-                    },
-                    0x0018 => {
-                        println!("FX18");
-                        self.st = self.v[((opcode & 0x0F00) >> 8) as usize];
-                        self.pc += 2;
-                        //TODO
-                    },
-                    0x001E => {
-                        println!("FX1E");
-                        self.i += self.v[((opcode & 0x0F00) >> 8) as usize] as u16;
-                        self.pc += 2;
-                    },
-                    0x0029 => {
-                        println!("FX29");
-                        let x = (opcode & 0x0F00) >> 8;
-                        let vx = self.v[x as usize];
-                        self.i = (0x50 + 5 * vx) as u16; 
-                        self.pc += 2;
-                    },
-                    0x0033 => {
-                        println!("FX33");
-                        let x = (opcode & 0x0F00) >> 8;
-                        let mut vx = self.v[x as usize];
+            let pc = core.pc;
+            let outcome = core.step();
+            match policy {
+                FaultPolicy::Halt => {
+                    assert_eq!(outcome, Err(fault.clone()));
+                    assert_eq!(core.halted(), Some(&fault));
+                    assert_eq!(core.pc, pc);
+                    // A halted machine stays halted
+                    assert_eq!(core.step(), Err(fault.clone()));
+                    assert_eq!(core.pc, pc);
+                },
+                FaultPolicy::Nop => {
+                    assert_eq!(outcome, Ok(StepOutcome::Skipped(fault.clone())));
+                    assert_eq!(core.halted(), None);
+                    assert_eq!(core.pc, pc + 2);
+                },
+                FaultPolicy::Trap => {
+                    assert_eq!(outcome, Ok(StepOutcome::Trapped(fault.clone())));
+                    assert_eq!(core.halted(), None);
+                    assert_eq!(core.pc, pc);
+                },
+            }
+        }
+    }
 
-                        self.memory[(self.i + 2) as usize] = (vx % 100) % 10; //ones place
-                        self.memory[(self.i + 1) as usize] = (vx / 10) % 10; //tens place
-                        self.memory[(self.i) as usize] = vx / 100; //hundreds place
+    #[test]
+    fn stack_overflow() {
+        // CALL 0x200 calls itself; the 17th call has no slot left
+        check_fault(&[0x2200], 16, Chip8Error::StackOverflow { pc: 0x200 });
+    }
 
-                        println!("{} {} {}", self.memory[(self.i) as usize], self.memory[(self.i + 1) as usize], self.memory[(self.i + 2) as usize]);
+    #[test]
+    fn stack_underflow() {
+        check_fault(&[0x00EE], 0, Chip8Error::StackUnderflow { pc: 0x200 });
+    }
 
-                        self.pc += 2;
-                    },
-                    0x0055 => {
-                        println!("FX55");
-                        let x = (opcode & 0x0F00) >> 8;
-                        let vx = (self.v[x as usize] & 0x000F) as u16;
+    #[test]
+    fn invalid_opcode() {
+        check_fault(&[0x6000, 0x5121], 1, Chip8Error::InvalidOpcode { pc: 0x202, opcode: 0x5121 });
+    }
 
-                        for ind in 0..(vx + 1) {
-                            self.memory[(self.i + ind) as usize] = self.v[ind as usize];
-                        }
+    #[test]
+    fn memory_out_of_bounds() {
+        // FX55/FX65 with X = 2 touch I..=I+2
+        check_fault(&[0xAFFE, 0xF255], 1, Chip8Error::MemoryOutOfBounds { pc: 0x202, addr: 0x1000 });
+        check_fault(&[0xAFFE, 0xF265], 1, Chip8Error::MemoryOutOfBounds { pc: 0x202, addr: 0x1000 });
+        check_fault(&[0xAFFC, 0xD005], 1, Chip8Error::MemoryOutOfBounds { pc: 0x202, addr: 0x1000 });
+        check_fault(&[0xAFFF, 0xF033], 1, Chip8Error::MemoryOutOfBounds { pc: 0x202, addr: 0x1001 });
+        // The last byte of memory itself is fine
+        let mut core = machine(Quirks::default(), &[0xAFFD, 0xF255]);
+        core.step().unwrap();
+        assert_eq!(core.step(), Ok(StepOutcome::Executed));
+    }
 
-                        self.pc += 2;
-                    },
-                    0x0065 => {
-                        println!("FX65");
-                        let x = (opcode & 0x0F00) >> 8;
-                        let vx = (self.v[x as usize] & 0x000F) as u16;
+    #[test]
+    fn largest_rom_fits() {
+        let mut core = Chip8::new();
+        assert_eq!(MAX_ROM_SIZE, 3584);
+        core.load_rom_bytes(&[0xAB; 3584]).unwrap();
+        assert_eq!(core.rom().len(), 3584);
+        assert_eq!(core.memory[MEMORY_SIZE - 1], 0xAB);
 
-                        for ind in 0..(x + 1) {
-                            self.v[ind as usize] = self.memory[(self.i + ind) as usize];
-                        }
+        assert!(matches!(core.load_rom_bytes(&[0; 3585]), Err(RomError::TooLarge(3585))));
+        // A rejected ROM leaves the loaded one in place
+        assert_eq!(core.rom().len(), 3584);
+    }
 
-                        self.i += x + 1;
+    fn presets() -> [(&'static str, Quirks); 4] {
+        [("vip", Quirks::cosmac_vip()), ("chip48", Quirks::chip48()),
+         ("schip", Quirks::superchip()), ("xochip", Quirks::xochip())]
+    }
 
-                        self.pc += 2;
-                    },
-                    _ => {
-                        println!("Invalid opcode: {:X}", opcode);
-                    }
-                }
-            },
+    /** Runs the first `count` instructions of `program` under `quirks` */
+    fn run(quirks: Quirks, program: &[u16], count: usize) -> Chip8 {
+        let mut core = machine(quirks, program);
+        for _ in 0..count {
+            assert_eq!(core.step(), Ok(StepOutcome::Executed));
+        }
+        core
+    }
 
-            _ => {
-                println!("Invalid opcode: {:X}", opcode);
+    #[test]
+    fn shift_source_quirk() {
+        // V1 = 0x81, V2 = 0x03, then SHR V1, V2 or SHL V1, V2
+        for (name, quirks) in presets().iter() {
+            let shr = run(*quirks, &[0x6181, 0x6203, 0x8126], 3);
+            let shl = run(*quirks, &[0x6181, 0x6203, 0x812E], 3);
+            if quirks.shift_vx {
+                assert_eq!((shr.v[1], shr.v[15]), (0x40, 1), "{}", name);
+                assert_eq!((shl.v[1], shl.v[15]), (0x02, 1), "{}", name);
+            } else {
+                assert_eq!((shr.v[1], shr.v[15]), (0x01, 1), "{}", name);
+                assert_eq!((shl.v[1], shl.v[15]), (0x06, 0), "{}", name);
             }
         }
-        //Timers 
-        //self.tcount += 1;
-        //if self.tcount >= self.tinterval {
-            //self.tcount = 0;
-            if self.dt > 0 {
-                self.dt -= 1;
+    }
+
+    #[test]
+    fn load_store_quirk() {
+        let expected = [("vip", 0x303), ("chip48", 0x302), ("schip", 0x300), ("xochip", 0x303)];
+        for (&(name, quirks), &(_, i)) in presets().iter().zip(expected.iter()) {
+            let save = run(quirks, &[0xA300, 0x6001, 0x6102, 0x6203, 0xF255], 5);
+            assert_eq!(&save.memory[0x300..0x304], &[1, 2, 3, 0], "{}", name);
+            assert_eq!(save.i, i, "{}", name);
+
+            let mut load = machine(quirks, &[0xA300, 0xF265]);
+            load.memory[0x300..0x304].copy_from_slice(&[7, 8, 9, 10]);
+            load.step().unwrap();
+            load.step().unwrap();
+            assert_eq!(&load.v[..4], &[7, 8, 9, 0], "{}", name);
+            assert_eq!(load.i, i, "{}", name);
+        }
+    }
+
+    #[test]
+    fn jump_offset_quirk() {
+        // V0 = 0x10, V2 = 0x20, then JP V0, 0x234
+        for (name, quirks) in presets().iter() {
+            let core = run(*quirks, &[0x6010, 0x6220, 0xB234], 3);
+            let expected = if quirks.jump_with_vx { 0x254 } else { 0x244 };
+            assert_eq!(core.pc, expected, "{}", name);
+        }
+    }
+
+    #[test]
+    fn vf_reset_quirk() {
+        for &op in [0x8011, 0x8012, 0x8013].iter() {
+            for (name, quirks) in presets().iter() {
+                let core = run(*quirks, &[0x6F05, 0x6003, 0x6105, op], 4);
+                let expected = if quirks.vf_reset { 0 } else { 5 };
+                assert_eq!(core.v[15], expected, "{} {:04X}", name, op);
             }
-            if self.st > 0 {
-                self.st -= 1;
+        }
+    }
+
+    #[test]
+    fn sprite_wrap_quirk() {
+        // The 4x5 "0" glyph at (62, 30) runs off the right and bottom edges
+        for (name, quirks) in presets().iter() {
+            let core = run(*quirks, &[0x603E, 0x611E, 0xA050, 0xD015], 4);
+            let screen = core.framebuffer();
+            assert!(screen[30][62] && screen[30][63] && screen[31][62], "{}", name);
+            let wrapped = [screen[30][0], screen[30][1], screen[0][62], screen[2][1]];
+            assert_eq!(wrapped, [quirks.wrap_sprites; 4], "{}", name);
+        }
+        // The starting position wraps whatever the quirk
+        let core = run(Quirks::cosmac_vip(), &[0x6042, 0x6122, 0xA050, 0xD011], 4);
+        assert!(core.framebuffer()[2][2]);
+    }
+
+    #[test]
+    fn display_wait_quirk() {
+        for (name, quirks) in presets().iter() {
+            let mut core = machine(*quirks, &[0xA050, 0xD005, 0xD005, 0xD005]);
+            let reason = core.run_until(4);
+            if quirks.display_wait {
+                assert_eq!(reason, StopReason::VBlank, "{}", name);
+                assert_eq!(core.pc, 0x204, "{}", name);
+            } else {
+                assert_eq!(reason, StopReason::Limit, "{}", name);
+                assert_eq!(core.pc, 0x208, "{}", name);
             }
-        //}
+        }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn run_frame_runs_ipf_instructions_and_ticks_once() {
+        let mut core = machine(Quirks::default(), &[0x7001; 30]);
+        core.ipf = 12;
+        core.dt = 5;
+        core.st = 1;
+        assert!(core.sound_active());
+
+        assert_eq!(core.run_frame(), Ok(StepOutcome::Executed));
+        assert_eq!(core.v[0], 12);
+        assert_eq!(core.pc, 0x200 + 2 * 12);
+        assert_eq!((core.dt, core.st), (4, 0));
+        assert!(!core.sound_active());
+
+        core.run_frame().unwrap();
+        assert_eq!(core.v[0], 24);
+        assert_eq!((core.dt, core.st), (3, 0));
+    }
+
+    #[test]
+    fn timers_stop_at_zero() {
+        let mut core = machine(Quirks::default(), &[0x6002, 0xF018, 0xF015]);
+        for _ in 0..3 {
+            core.step().unwrap();
+        }
+        assert_eq!((core.dt, core.st), (2, 2));
+        assert!(core.sound_active());
+        for expected in [1, 0, 0, 0].iter() {
+            core.tick_timers();
+            assert_eq!((core.dt, core.st), (*expected, *expected));
+            assert_eq!(core.sound_active(), *expected > 0);
+        }
+    }
+
+    #[test]
+    fn key_wait_needs_press_and_release() {
+        let mut core = machine(Quirks::default(), &[0xF30A, 0x6001]);
+        core.set_key(0x5, true);
+        // Key 5 was already down when FX0A started, so it is ignored
+        for _ in 0..3 {
+            core.step().unwrap();
+            assert_eq!(core.pc, 0x200);
+        }
+        core.set_key(0x7, true);
+        core.step().unwrap();
+        assert_eq!(core.pc, 0x200, "waits for the release");
+        core.set_key(0x5, false);
+        core.step().unwrap();
+        assert_eq!(core.pc, 0x200);
+        core.set_key(0x7, false);
+        core.step().unwrap();
+        assert_eq!(core.pc, 0x202);
+        assert_eq!(core.v[3], 0x7);
+    }
+
+    #[test]
+    fn key_wait_accepts_a_held_key_once_pressed_again() {
+        let mut core = machine(Quirks::default(), &[0xF30A]);
+        core.set_key(0xC, true);
+        core.step().unwrap();
+        core.set_key(0xC, false);
+        core.step().unwrap();
+        core.set_key(0xC, true);
+        core.step().unwrap();
+        core.set_key(0xC, false);
+        core.step().unwrap();
+        assert_eq!((core.pc, core.v[3]), (0x202, 0xC));
+    }
+
+    #[test]
+    fn key_wait_survives_a_save_state() {
+        let mut core = machine(Quirks::default(), &[0xF30A]);
+        core.set_key(0x1, true);
+        core.step().unwrap();
+        let state = SaveState::from_bytes(&core.save_state().to_bytes()).unwrap();
+        let mut restored = machine(Quirks::default(), &[0xF30A]);
+        restored.load_state(&state).unwrap();
+        restored.step().unwrap();
+        assert_eq!(restored.pc, 0x200, "key 1 is still ignored");
+    }
+
+    #[test]
+    fn key_skips() {
+        // V1 = 0xA, then SKP V1 / SKNP V1
+        for &(op, down, skips) in [(0xE19E, true, true), (0xE19E, false, false),
+                                    (0xE1A1, true, false), (0xE1A1, false, true)].iter() {
+            let mut core = machine(Quirks::default(), &[0x610A, op]);
+            core.set_key(0xA, down);
+            core.set_key(0xB, !down);
+            core.step().unwrap();
+            core.step().unwrap();
+            assert_eq!(core.pc, if skips { 0x206 } else { 0x204 }, "{:04X} with key {}", op, down);
+        }
+    }
+
+    #[test]
+    fn reset_restores_the_loaded_rom() {
+        // Calls a routine that draws, sets the timers and overwrites its own code
+        let program = [0x2204, 0x1202, 0x6A07, 0xFA15, 0xFA18, 0xA050, 0xD005, 0xA200, 0xF055];
+        let mut core = machine(Quirks::chip48(), &program);
+        core.ipf = 25;
+        core.fault_policy = FaultPolicy::Trap;
+        for _ in 0..8 {
+            core.step().unwrap();
+        }
+        core.set_key(0x4, true);
+        assert_eq!(core.sp, 1);
+        assert!(core.framebuffer().iter().flatten().any(|&on| on));
+        assert_ne!(&core.memory[0x200..0x202], &[0x22, 0x04]);
+
+        core.reset();
+        assert_eq!(core.v, [0; 16]);
+        assert_eq!((core.i, core.dt, core.st, core.sp), (0, 0, 0, 0));
+        assert_eq!(core.stack, [0; 16]);
+        assert_eq!(core.pc, PROGRAM_START);
+        assert!(core.framebuffer().iter().flatten().all(|&on| !on));
+        assert!((0..16).all(|key| !core.is_key_down(key)));
+        assert_eq!(core.cycles(), 0);
+
+        let rom: Vec<u8> = program.iter().flat_map(|word| word.to_be_bytes().to_vec()).collect();
+        assert_eq!(core.rom(), &rom[..]);
+        assert_eq!(&core.memory[0x200..0x200 + rom.len()], &rom[..]);
+        assert_eq!(&core.memory[FONT_ADDR as usize..FONT_ADDR as usize + FONTSET.len()], &FONTSET[..]);
+        assert_eq!(core.quirks, Quirks::chip48());
+        assert_eq!(core.ipf, 25);
+        assert_eq!(core.fault_policy, FaultPolicy::Trap);
+    }
+
+    #[test]
+    fn zero_height_sprite_with_a_watch() {
+        // DXY0 with I = 0 touches no memory, so no watch fires
+        let mut core = machine(Quirks::chip48(), &[0xA000, 0xD010, 0x6001]);
+        core.breakpoints.watch(Watch { start: 0x300, end: 0x300, read: true, write: true });
+        core.breakpoints.watch(Watch { start: 0x000, end: 0x000, read: true, write: true });
+        assert_eq!(core.run_until(3), StopReason::Limit);
+        assert_eq!(core.v[0], 1);
+    }
+
+    #[test]
+    fn breakpoint_stops_before_its_instruction() {
+        // V0 += 1 in a loop with a breakpoint on the add
+        let mut core = machine(Quirks::default(), &[0x7001, 0x1200]);
+        core.breakpoints.add(0x200);
+        assert_eq!(core.run_until(100), StopReason::Breakpoint(0x200));
+        assert_eq!((core.pc, core.v[0]), (0x200, 0));
+        // Running again leaves the breakpoint, then stops on the next pass
+        assert_eq!(core.run_until(100), StopReason::Breakpoint(0x200));
+        assert_eq!(core.v[0], 1);
+
+        // step forgets the breakpoint it stopped at; skip_breakpoint resumes past it
+        core.step().unwrap();
+        core.step().unwrap();
+        assert_eq!(core.run_until(100), StopReason::Breakpoint(0x200));
+        assert_eq!(core.v[0], 2);
+        core.skip_breakpoint();
+        assert_eq!(core.run_until(100), StopReason::Breakpoint(0x200));
+        assert_eq!(core.v[0], 3);
+    }
+
+    #[test]
+    fn memory_watch_stops_after_the_access() {
+        let program = [0xA300, 0x6007, 0xF055, 0xF065, 0x1208];
+        let mut core = machine(Quirks::default(), &program);
+        core.breakpoints.watch(Watch { start: 0x300, end: 0x300, read: false, write: true });
+        assert_eq!(core.run_until(100), StopReason::Memory { pc: 0x204, addr: 0x300, access: Access::Write });
+        assert_eq!((core.pc, core.memory[0x300]), (0x206, 7));
+        assert_eq!(core.run_until(100), StopReason::Limit, "reads are not watched");
+
+        let mut core = machine(Quirks::default(), &program);
+        // FX55 left I at 0x301, so FX65 reads the first byte of this range
+        core.breakpoints.watch(Watch { start: 0x301, end: 0x302, read: true, write: false });
+        assert_eq!(core.run_until(100), StopReason::Memory { pc: 0x206, addr: 0x301, access: Access::Read });
+    }
+
+    #[test]
+    fn register_watch_stops_on_change() {
+        let mut core = machine(Quirks::default(), &[0x6005, 0x6005, 0x6105, 0x6006, 0x1208]);
+        core.breakpoints.watch_register(Register::V(0));
+        assert_eq!(core.run_until(100),
+                   StopReason::Register { pc: 0x200, register: Register::V(0), old: 0, new: 5 });
+        // Writing the same value and changing V1 do not stop
+        assert_eq!(core.run_until(100),
+                   StopReason::Register { pc: 0x206, register: Register::V(0), old: 5, new: 6 });
+        assert_eq!(core.run_until(100), StopReason::Limit);
+    }
+
+    #[test]
+    fn run_until_stops_at_the_limit() {
+        let mut core = machine(Quirks::default(), &[0x7001, 0x1200]);
+        core.dt = 3;
+        assert_eq!(core.run_until(7), StopReason::Limit);
+        assert_eq!((core.pc, core.v[0], core.cycles()), (0x202, 4, 7));
+        assert_eq!(core.dt, 3, "run_until does not tick the timers");
+    }
+}
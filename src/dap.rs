@@ -0,0 +1,692 @@
+/*  dap.rs
+ *  Debug Adapter Protocol server, so ROMs can be stepped from VS Code or any
+ *  other DAP client. The session runs over any reader and writer; the SDL
+ *  binary's `--dap` serves it on stdin and stdout, headless. Breakpoints and
+ *  watchpoints are the core's own (see `Chip8::run_until`).
+ *
+ *  The ROM is presented as a generated source, one line per instruction word
+ *  from `PROGRAM_START`, so breakpoints can be set on lines as well as from
+ *  the disassembly view. */
+
+use std::fs::File;
+use std::io::{self, BufRead, Read, Write};
+use std::path::PathBuf;
+use std::sync::mpsc::{self, TryRecvError};
+use std::thread;
+
+use serde_json::{self, Value};
+
+use chip8::{Chip8, MEMORY_SIZE, PROGRAM_START, TIMER_HZ};
+use debug::{Register, StopReason};
+use error::StepOutcome;
+use instruction::Instruction;
+use number;
+use pacer::FramePacer;
+use quirks::{Quirks, PRESET_NAMES};
+
+/// The machine is presented as a single thread.
+const THREAD_ID: u64 = 1;
+/// The only source: the ROM listing.
+const SOURCE_REF: u64 = 1;
+/// Variable containers, as returned by `scopes`.
+const REGISTERS_REF: u64 = 1;
+const STACK_REF: u64 = 2;
+
+/// Where a run started by `continue` or a step ends, besides the core's own
+/// breakpoints and watchpoints.
+#[derive(Clone, Copy)]
+enum Goal {
+    /// Run until something stops the machine
+    Continue,
+    /// Back at `pc` with the call stack `sp` deep, after a call returns
+    Return { pc: u16, sp: u8 },
+    /// The call stack drops below `sp` entries
+    Out { sp: u8 },
+}
+
+impl Goal {
+    fn reached(&self, core: &Chip8) -> bool {
+        match *self {
+            Goal::Continue => false,
+            Goal::Return { pc, sp } => core.pc == pc && core.sp == sp,
+            Goal::Out { sp } => core.sp < sp,
+        }
+    }
+}
+
+/// Whether the server keeps going after a request.
+#[derive(PartialEq)]
+enum Flow {
+    Continue,
+    Exit,
+}
+
+/** Serves one debug session until the client disconnects or closes
+ *  `input`. `configure` builds the machine a launched ROM runs on, before the
+ *  launch request's own `quirks`, `ipf` and `seed` are applied to it. */
+pub fn serve_on<R, W, F>(input: R, output: W, configure: F) -> Result<(), String>
+    where R: BufRead + Send + 'static, W: Write, F: FnMut(&[u8]) -> Result<Chip8, String>
+{
+    // Requests are read on their own thread so a running machine can be
+    // paused or stopped while it runs.
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        let mut input = input;
+        while let Ok(Some(message)) = read_message(&mut input) {
+            if tx.send(message).is_err() {
+                break;
+            }
+        }
+    });
+
+    let mut session = Session::new(output, configure);
+    let mut pacer = FramePacer::new(TIMER_HZ);
+    loop {
+        let message = if session.goal.is_some() {
+            match rx.try_recv() {
+                Ok(message) => Some(message),
+                Err(TryRecvError::Empty) => None,
+                Err(TryRecvError::Disconnected) => return Ok(()),
+            }
+        } else {
+            match rx.recv() {
+                Ok(message) => Some(message),
+                Err(_) => return Ok(()),
+            }
+        };
+
+        let result = match message {
+            Some(message) => session.handle(&message),
+            None => {
+                pacer.wait();
+                session.run_frame().map(|_| Flow::Continue)
+            },
+        };
+        match result {
+            Ok(Flow::Continue) => {},
+            Ok(Flow::Exit) => return Ok(()),
+            Err(e) => return Err(format!("writing to the client: {}", e)),
+        }
+    }
+}
+
+/** Reads one `Content-Length` framed message, or `None` at end of input */
+pub fn read_message<R: BufRead>(input: &mut R) -> io::Result<Option<Value>> {
+    let mut length = None;
+    loop {
+        let mut line = String::new();
+        if input.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            if length.is_some() {
+                break;
+            }
+            continue;
+        }
+        if let Some(colon) = line.find(':') {
+            if line[..colon].eq_ignore_ascii_case("Content-Length") {
+                length = line[colon + 1..].trim().parse::<usize>().ok();
+            }
+        }
+    }
+
+    let mut body = vec![0; length.unwrap_or(0)];
+    input.read_exact(&mut body)?;
+    serde_json::from_slice(&body).map(Some).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/** Writes one message with its `Content-Length` header */
+pub fn write_message<W: Write>(out: &mut W, message: &Value) -> io::Result<()> {
+    let body = message.to_string();
+    write!(out, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    out.flush()
+}
+
+struct Session<W: Write, F> {
+    out: W,
+    configure: F,
+    /// Sequence number of the next message sent
+    seq: u64,
+    /// The machine, once a ROM has been launched
+    core: Option<Chip8>,
+    rom_name: String,
+    stop_on_entry: bool,
+    /// Set while the machine runs
+    goal: Option<Goal>,
+    /// Breakpoints from `setBreakpoints`, as (id, line), and from
+    /// `setInstructionBreakpoints`; the core holds the union of both. Lines
+    /// may be set before launch, so they are resolved against each ROM.
+    line_breakpoints: Vec<(u64, u64)>,
+    instruction_breakpoints: Vec<u16>,
+    next_breakpoint_id: u64,
+}
+
+impl<W: Write, F: FnMut(&[u8]) -> Result<Chip8, String>> Session<W, F> {
+    fn new(out: W, configure: F) -> Session<W, F> {
+        Session {
+            out,
+            configure,
+            seq: 1,
+            core: None,
+            rom_name: String::new(),
+            stop_on_entry: false,
+            goal: None,
+            line_breakpoints: Vec::new(),
+            instruction_breakpoints: Vec::new(),
+            next_breakpoint_id: 1,
+        }
+    }
+
+    fn send(&mut self, mut message: Value) -> io::Result<()> {
+        message["seq"] = json!(self.seq);
+        self.seq += 1;
+        write_message(&mut self.out, &message)
+    }
+
+    fn event(&mut self, event: &str, body: Value) -> io::Result<()> {
+        self.send(json!({ "type": "event", "event": event, "body": body }))
+    }
+
+    fn stopped(&mut self, reason: &str, text: Option<String>) -> io::Result<()> {
+        self.goal = None;
+        let mut body = json!({ "reason": reason, "threadId": THREAD_ID, "allThreadsStopped": true });
+        if let Some(text) = text {
+            body["text"] = json!(text);
+        }
+        self.event("stopped", body)
+    }
+
+    fn handle(&mut self, message: &Value) -> io::Result<Flow> {
+        if message["type"] != "request" {
+            return Ok(Flow::Continue);
+        }
+        let command = message["command"].as_str().unwrap_or("").to_string();
+        let args = &message["arguments"];
+
+        let result = match command.as_str() {
+            "initialize" => Ok(capabilities()),
+            "launch" => self.launch(args),
+            "setBreakpoints" => self.set_breakpoints(args),
+            "setInstructionBreakpoints" => self.set_instruction_breakpoints(args),
+            "dataBreakpointInfo" => Ok(data_breakpoint_info(args)),
+            "setDataBreakpoints" => self.set_data_breakpoints(args),
+            "configurationDone" => Ok(Value::Null),
+            "threads" => Ok(json!({ "threads": [{ "id": THREAD_ID, "name": "CHIP-8" }] })),
+            "stackTrace" => self.core().map(|core| self.stack_trace(core)),
+            "scopes" => Ok(json!({ "scopes": [
+                { "name": "Registers", "variablesReference": REGISTERS_REF, "expensive": false },
+                { "name": "Stack", "variablesReference": STACK_REF, "expensive": false },
+            ] })),
+            "variables" => self.core().map(|core| variables(core, args)),
+            "setVariable" => self.set_variable(args),
+            "evaluate" => self.core().and_then(|core| evaluate(core, args)),
+            "source" => self.core().map(|core| json!({ "content": listing(core.rom()) })),
+            "disassemble" => self.core().and_then(|core| disassemble(core, args)),
+            "continue" => self.core().map(|_| json!({ "allThreadsContinued": true })),
+            "next" | "stepIn" | "stepOut" | "pause" => self.core().map(|_| Value::Null),
+            "disconnect" => Ok(Value::Null),
+            "terminate" => Ok(Value::Null),
+            _ => Err(format!("unsupported request `{}`", command)),
+        };
+
+        let ok = result.is_ok();
+        let mut response = json!({
+            "type": "response",
+            "request_seq": message["seq"],
+            "command": command,
+            "success": ok,
+        });
+        match result {
+            Ok(Value::Null) => {},
+            Ok(body) => response["body"] = body,
+            Err(e) => response["message"] = json!(e),
+        }
+        self.send(response)?;
+        if !ok {
+            return Ok(Flow::Continue);
+        }
+
+        // Events that must follow the response
+        match command.as_str() {
+            "initialize" => self.event("initialized", json!({}))?,
+            "launch" => {
+                // Lines set before this ROM was loaded can be verified now
+                let rom_len = self.core.as_ref().map(|core| core.rom().len());
+                let breakpoints: Vec<Value> = self.line_breakpoints.iter()
+                    .map(|&(id, line)| line_breakpoint(id, line, rom_len))
+                    .collect();
+                for breakpoint in breakpoints {
+                    self.event("breakpoint", json!({ "reason": "changed", "breakpoint": breakpoint }))?;
+                }
+            },
+            "configurationDone" => {
+                if self.stop_on_entry {
+                    self.stopped("entry", None)?;
+                } else {
+                    self.resume(Goal::Continue);
+                }
+            },
+            "continue" => self.resume(Goal::Continue),
+            // Already stopped: the client has had its `stopped` event
+            "pause" if self.goal.is_some() => self.stopped("pause", None)?,
+            "next" => self.step_over()?,
+            "stepIn" => self.step_in()?,
+            "stepOut" => {
+                let sp = self.core.as_ref().map_or(0, |core| core.sp);
+                if sp == 0 {
+                    self.step_in()?;
+                } else {
+                    self.resume(Goal::Out { sp });
+                }
+            },
+            "terminate" => {
+                self.goal = None;
+                self.event("terminated", json!({}))?;
+            },
+            "disconnect" => return Ok(Flow::Exit),
+            _ => {},
+        }
+        Ok(Flow::Continue)
+    }
+
+    fn core(&self) -> Result<&Chip8, String> {
+        self.core.as_ref().ok_or_else(|| "no ROM has been launched".to_string())
+    }
+
+    fn core_mut(&mut self) -> Result<&mut Chip8, String> {
+        self.core.as_mut().ok_or_else(|| "no ROM has been launched".to_string())
+    }
+
+    /** Loads `program` on the machine from `configure`, overriding its
+     *  settings with `quirks`, `ipf` and `seed` from the launch arguments */
+    fn launch(&mut self, args: &Value) -> Result<Value, String> {
+        let path = match args["program"].as_str() {
+            Some(path) => PathBuf::from(path),
+            None => return Err("launch needs a `program` to run".to_string()),
+        };
+        let mut rom = Vec::new();
+        File::open(&path)
+            .and_then(|mut file| file.read_to_end(&mut rom))
+            .map_err(|e| format!("{}: {}", path.display(), e))?;
+
+        let mut core = (self.configure)(&rom)?;
+        if let Some(name) = args["quirks"].as_str() {
+            core.quirks = Quirks::from_name(name).ok_or_else(|| {
+                format!("unknown quirks profile `{}` (expected one of {})", name, PRESET_NAMES.join(", "))
+            })?;
+        }
+        if let Some(ipf) = args["ipf"].as_u64() {
+            core.ipf = ipf.clamp(1, u32::MAX as u64) as u32;
+        }
+        if let Some(seed) = args["seed"].as_u64() {
+            core.seed_rng(seed);
+        }
+        core.load_rom_bytes(&rom).map_err(|e| format!("{}: {}", path.display(), e))?;
+
+        self.rom_name = path.file_name().map_or("rom".to_string(), |name| name.to_string_lossy().into_owned());
+        self.stop_on_entry = args["stopOnEntry"].as_bool().unwrap_or(false);
+        self.core = Some(core);
+        self.sync_breakpoints();
+        Ok(Value::Null)
+    }
+
+    /** Line breakpoints in the ROM listing; replaces all previous ones */
+    fn set_breakpoints(&mut self, args: &Value) -> Result<Value, String> {
+        let rom_len = self.core.as_ref().map(|core| core.rom().len());
+        let mut lines = Vec::new();
+        let mut results = Vec::new();
+        for breakpoint in args["breakpoints"].as_array().into_iter().flatten() {
+            let line = breakpoint["line"].as_u64().unwrap_or(0);
+            let id = self.next_breakpoint_id;
+            self.next_breakpoint_id += 1;
+            lines.push((id, line));
+            results.push(line_breakpoint(id, line, rom_len));
+        }
+        self.line_breakpoints = lines;
+        self.sync_breakpoints();
+        Ok(json!({ "breakpoints": results }))
+    }
+
+    /** Breakpoints set from the disassembly view; replaces all previous ones */
+    fn set_instruction_breakpoints(&mut self, args: &Value) -> Result<Value, String> {
+        let mut addresses = Vec::new();
+        let mut results = Vec::new();
+        for breakpoint in args["breakpoints"].as_array().into_iter().flatten() {
+            let base = breakpoint["instructionReference"].as_str().and_then(|text| number::parse(text, 10));
+            let addr = base.map(|base| base.saturating_add(breakpoint["offset"].as_i64().unwrap_or(0)));
+            match addr {
+                Some(addr) if addr >= 0 && (addr as usize) < MEMORY_SIZE => {
+                    addresses.push(addr as u16);
+                    results.push(json!({ "verified": true, "instructionReference": format_address(addr) }));
+                },
+                _ => results.push(json!({ "verified": false, "message": "outside memory" })),
+            }
+        }
+        self.instruction_breakpoints = addresses;
+        self.sync_breakpoints();
+        Ok(json!({ "breakpoints": results }))
+    }
+
+    /** Register watchpoints; replaces all previous ones */
+    fn set_data_breakpoints(&mut self, args: &Value) -> Result<Value, String> {
+        let core = self.core_mut()?;
+        core.breakpoints.clear_watches();
+        let mut results = Vec::new();
+        for breakpoint in args["breakpoints"].as_array().into_iter().flatten() {
+            match breakpoint["dataId"].as_str().and_then(Register::from_name) {
+                Some(register) => {
+                    core.breakpoints.watch_register(register);
+                    results.push(json!({ "verified": true }));
+                },
+                None => results.push(json!({ "verified": false, "message": "not a register" })),
+            }
+        }
+        Ok(json!({ "breakpoints": results }))
+    }
+
+    fn sync_breakpoints(&mut self) {
+        if let Some(ref mut core) = self.core {
+            let rom_len = core.rom().len();
+            let lines = self.line_breakpoints.iter().filter_map(|&(_, line)| line_address(line, rom_len));
+            core.breakpoints.clear_addresses();
+            for addr in lines.chain(self.instruction_breakpoints.iter().cloned()) {
+                core.breakpoints.add(addr);
+            }
+        }
+    }
+
+    fn set_variable(&mut self, args: &Value) -> Result<Value, String> {
+        let name = args["name"].as_str().unwrap_or("");
+        let text = args["value"].as_str().unwrap_or("");
+        let value = number::parse(text, 10).ok_or_else(|| format!("`{}` is not a number", text))?;
+        if args["variablesReference"].as_u64() != Some(REGISTERS_REF) {
+            return Err("only registers can be changed".to_string());
+        }
+
+        let core = self.core_mut()?;
+        let fits = |max: i64| if value >= 0 && value <= max { Ok(value) } else { Err(format!("{} does not fit in {}", text, name)) };
+        match name {
+            "PC" => core.pc = fits(MEMORY_SIZE as i64 - 1)? as u16,
+            "SP" => core.sp = fits(core.stack.len() as i64)? as u8,
+            "I" => core.i = fits(0xFFFF)? as u16,
+            _ => match Register::from_name(name) {
+                Some(Register::V(x)) => core.v[x as usize] = fits(0xFF)? as u8,
+                Some(Register::Dt) => core.dt = fits(0xFF)? as u8,
+                Some(Register::St) => core.st = fits(0xFF)? as u8,
+                _ => return Err(format!("unknown register `{}`", name)),
+            },
+        }
+        let value = register_value(core, name).unwrap_or_default();
+        Ok(json!({ "value": value }))
+    }
+
+    fn stack_trace(&self, core: &Chip8) -> Value {
+        let depth = (core.sp as usize).min(core.stack.len());
+        let mut frames = Vec::new();
+        // Frame 0 is where the machine is; each stack entry adds the call
+        // that is waiting to return.
+        for level in 0..=depth {
+            let pc = if level == 0 { core.pc } else { core.stack[depth - level].wrapping_sub(2) };
+            let name = if level < depth {
+                routine_name(core, core.stack[depth - 1 - level].wrapping_sub(2))
+            } else {
+                "main".to_string()
+            };
+            let mut frame = json!({
+                "id": level,
+                "name": name,
+                "line": 0,
+                "column": 0,
+                "instructionPointerReference": format_address(pc as i64),
+            });
+            if let Some(line) = address_line(pc, core.rom().len()) {
+                frame["source"] = self.source();
+                frame["line"] = json!(line);
+                frame["column"] = json!(1);
+            }
+            frames.push(frame);
+        }
+        json!({ "stackFrames": frames, "totalFrames": depth + 1 })
+    }
+
+    fn source(&self) -> Value {
+        json!({ "name": format!("{} (disassembly)", self.rom_name), "sourceReference": SOURCE_REF })
+    }
+
+    /** Runs towards `goal`. Resuming never stops at the breakpoint the
+     *  machine is already on. */
+    fn resume(&mut self, goal: Goal) {
+        if let Some(ref mut core) = self.core {
+            core.skip_breakpoint();
+        }
+        self.goal = Some(goal);
+    }
+
+    /** Executes one instruction */
+    fn step_in(&mut self) -> io::Result<()> {
+        let outcome = match self.core {
+            Some(ref mut core) => core.step(),
+            None => return Ok(()),
+        };
+        match outcome {
+            Ok(StepOutcome::Executed) | Ok(StepOutcome::Skipped(_)) => self.stopped("step", None),
+            Ok(StepOutcome::Trapped(e)) | Err(e) => self.stopped("exception", Some(e.to_string())),
+        }
+    }
+
+    /** Executes one instruction, running a called subroutine to its return */
+    fn step_over(&mut self) -> io::Result<()> {
+        let call = match self.core {
+            Some(ref core) => match core.fetch(core.pc).and_then(Instruction::decode) {
+                Some(Instruction::Call(_)) => Some(Goal::Return { pc: core.pc.wrapping_add(2), sp: core.sp }),
+                _ => None,
+            },
+            None => return Ok(()),
+        };
+        match call {
+            Some(goal) => {
+                self.resume(goal);
+                Ok(())
+            },
+            None => self.step_in(),
+        }
+    }
+
+    /** Runs one 60 Hz frame towards the current goal, reporting a stop */
+    fn run_frame(&mut self) -> io::Result<()> {
+        let goal = match self.goal {
+            Some(goal) => goal,
+            None => return Ok(()),
+        };
+        let core = match self.core {
+            Some(ref mut core) => core,
+            None => return Ok(()),
+        };
+
+        let mut stop = None;
+        for _ in 0..core.ipf {
+            let reason = core.run_until(1);
+            match reason {
+                StopReason::Limit | StopReason::VBlank if goal.reached(core) => {
+                    stop = Some(("step", None));
+                    break;
+                },
+                StopReason::Limit => {},
+                StopReason::VBlank => break,
+                StopReason::Breakpoint(_) => {
+                    stop = Some(("breakpoint", None));
+                    break;
+                },
+                StopReason::Memory { .. } | StopReason::Register { .. } => {
+                    stop = Some(("data breakpoint", Some(reason.to_string())));
+                    break;
+                },
+                StopReason::Halted(e) | StopReason::Trapped(e) => {
+                    stop = Some(("exception", Some(e.to_string())));
+                    break;
+                },
+            }
+        }
+
+        match stop {
+            Some((reason, text)) => self.stopped(reason, text),
+            None => {
+                core.tick_timers();
+                Ok(())
+            },
+        }
+    }
+}
+
+fn capabilities() -> Value {
+    json!({
+        "supportsConfigurationDoneRequest": true,
+        "supportsDisassembleRequest": true,
+        "supportsInstructionBreakpoints": true,
+        "supportsSteppingGranularity": true,
+        "supportsSetVariable": true,
+        "supportsDataBreakpoints": true,
+        "supportsEvaluateForHovers": true,
+        "supportsTerminateRequest": true,
+    })
+}
+
+/** Registers can be watched for changes; `dataId` is the register name */
+fn data_breakpoint_info(args: &Value) -> Value {
+    let name = args["name"].as_str().unwrap_or("");
+    match Register::from_name(name) {
+        Some(register) if args["variablesReference"].as_u64() == Some(REGISTERS_REF) => json!({
+            "dataId": register.to_string(),
+            "description": format!("{} changes", register),
+            "accessTypes": ["write"],
+        }),
+        _ => json!({ "dataId": null, "description": "only registers can be watched" }),
+    }
+}
+
+fn variables(core: &Chip8, args: &Value) -> Value {
+    let variable = |name: String, value: String| json!({ "name": name, "value": value, "variablesReference": 0 });
+    let list: Vec<Value> = match args["variablesReference"].as_u64() {
+        Some(REGISTERS_REF) => {
+            let mut names: Vec<String> = (0..16).map(|x| format!("V{:X}", x)).collect();
+            names.extend(["I", "DT", "ST", "PC", "SP"].iter().map(|name| name.to_string()));
+            names.into_iter()
+                .map(|name| {
+                    let value = register_value(core, &name).unwrap_or_default();
+                    variable(name, value)
+                })
+                .collect()
+        },
+        Some(STACK_REF) => {
+            let depth = (core.sp as usize).min(core.stack.len());
+            core.stack[..depth].iter().enumerate()
+                .map(|(level, ret)| variable(format!("#{}", level), format_address(*ret as i64)))
+                .collect()
+        },
+        _ => Vec::new(),
+    };
+    json!({ "variables": list })
+}
+
+/** Evaluates a register name, for the watch panel and hovers */
+fn evaluate(core: &Chip8, args: &Value) -> Result<Value, String> {
+    let expression = args["expression"].as_str().unwrap_or("").trim();
+    match register_value(core, &expression.to_uppercase()) {
+        Some(value) => Ok(json!({ "result": value, "variablesReference": 0 })),
+        None => Err(format!("cannot evaluate `{}`; try a register name", expression)),
+    }
+}
+
+fn register_value(core: &Chip8, name: &str) -> Option<String> {
+    match name {
+        "PC" => Some(format_address(core.pc as i64)),
+        "SP" => Some(format!("{}", core.sp)),
+        "I" => Some(format!("0x{:03X}", core.i)),
+        _ => Register::from_name(name).map(|register| format!("0x{:02X}", core.register(register))),
+    }
+}
+
+/** `instructionCount` instructions starting `instructionOffset` words from
+ *  `memoryReference` plus `offset` bytes, as decoded by the core */
+fn disassemble(core: &Chip8, args: &Value) -> Result<Value, String> {
+    let base = args["memoryReference"].as_str().and_then(|text| number::parse(text, 10))
+        .ok_or_else(|| "disassemble needs a memoryReference".to_string())?;
+    // Clients scroll past either end of memory; those rows come back invalid
+    let start = base.saturating_add(args["offset"].as_i64().unwrap_or(0))
+        .saturating_add(args["instructionOffset"].as_i64().unwrap_or(0).saturating_mul(2));
+    let count = args["instructionCount"].as_i64().unwrap_or(0).clamp(0, MEMORY_SIZE as i64);
+
+    let instructions: Vec<Value> = (0..count).map(|n| {
+        let addr = start.saturating_add(2 * n);
+        let word = if addr >= 0 { core.fetch(addr as u16).filter(|_| addr < MEMORY_SIZE as i64) } else { None };
+        match word {
+            Some(word) => {
+                let text = Instruction::decode(word).map_or("???".to_string(), |instr| instr.to_string());
+                json!({ "address": format_address(addr), "instructionBytes": format!("{:04X}", word), "instruction": text })
+            },
+            None => json!({ "address": format_address(addr), "instruction": "", "presentationHint": "invalid" }),
+        }
+    }).collect();
+    Ok(json!({ "instructions": instructions }))
+}
+
+/** The ROM listing served as the session's source: one line per word */
+fn listing(rom: &[u8]) -> String {
+    let mut text = String::new();
+    for (n, pair) in rom.chunks(2).enumerate() {
+        let addr = PROGRAM_START as usize + 2 * n;
+        let word = (pair[0] as u16) << 8 | pair.get(1).cloned().unwrap_or(0) as u16;
+        match Instruction::decode(word) {
+            Some(instr) => text.push_str(&format!("{:03X}: {:04X}  {}\n", addr, word, instr)),
+            None => text.push_str(&format!("{:03X}: {:04X}\n", addr, word)),
+        }
+    }
+    text
+}
+
+/** Listing line (1-based) for `addr`, if it is inside the ROM */
+fn address_line(addr: u16, rom_len: usize) -> Option<u64> {
+    let offset = (addr as usize).checked_sub(PROGRAM_START as usize)?;
+    if offset < rom_len {
+        Some(offset as u64 / 2 + 1)
+    } else {
+        None
+    }
+}
+
+/** A line breakpoint as reported to the client; lines cannot be checked
+ *  until a ROM is launched */
+fn line_breakpoint(id: u64, line: u64, rom_len: Option<usize>) -> Value {
+    match rom_len {
+        Some(rom_len) if line_address(line, rom_len).is_some() => json!({ "id": id, "verified": true, "line": line }),
+        Some(_) => json!({ "id": id, "verified": false, "line": line, "message": "outside the ROM" }),
+        None => json!({ "id": id, "verified": false, "line": line, "message": "no ROM has been launched yet" }),
+    }
+}
+
+fn line_address(line: u64, rom_len: usize) -> Option<u16> {
+    let offset = (line.checked_sub(1)? * 2) as usize;
+    if offset < rom_len {
+        Some(PROGRAM_START + offset as u16)
+    } else {
+        None
+    }
+}
+
+/** Names the subroutine a call at `addr` enters, e.g. `sub_2A0` */
+fn routine_name(core: &Chip8, addr: u16) -> String {
+    match core.fetch(addr).and_then(Instruction::decode) {
+        Some(Instruction::Call(target)) => format!("sub_{:03X}", target),
+        _ => "sub_???".to_string(),
+    }
+}
+
+fn format_address(addr: i64) -> String {
+    if addr < 0 {
+        format!("-0x{:X}", addr.unsigned_abs())
+    } else {
+        format!("0x{:03X}", addr)
+    }
+}
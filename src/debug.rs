@@ -0,0 +1,312 @@
+/*  debug.rs
+ *  Breakpoints and watchpoints checked by `Chip8::run_until`. Keeping them in
+ *  the core means the console debugger, the DAP server and any other frontend
+ *  stop the machine in exactly the same places. Watchpoints only see accesses
+ *  made by instructions: instruction fetches, timer countdown and writes from
+ *  outside the core (a debugger poking memory) are not reported. */
+
+use std::collections::BTreeSet;
+use std::fmt;
+
+use error::Chip8Error;
+
+/// Direction of a memory access.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+}
+
+impl fmt::Display for Access {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Access::Read => write!(f, "read"),
+            Access::Write => write!(f, "write"),
+        }
+    }
+}
+
+/// Data memory touched by one instruction: `DXYN` and `FX65` read at I,
+/// `FX33` and `FX55` write there.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryAccess {
+    pub addr: u16,
+    pub len: u16,
+    pub access: Access,
+}
+
+/// A watched range of memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Watch {
+    /// First watched address
+    pub start: u16,
+    /// Last watched address, inclusive
+    pub end: u16,
+    /// Stop on reads from the range
+    pub read: bool,
+    /// Stop on writes to the range
+    pub write: bool,
+}
+
+impl Watch {
+    /** Whether `access` touches this range in a watched direction, and if so
+     *  the first watched address it touched */
+    pub fn hit(&self, access: &MemoryAccess) -> Option<u16> {
+        let watched = match access.access {
+            Access::Read => self.read,
+            Access::Write => self.write,
+        };
+        // `DXY0` draws nothing and touches no memory
+        if !watched || access.len == 0 {
+            return None;
+        }
+        let last = access.addr as usize + access.len as usize - 1;
+        if last < self.start as usize || access.addr > self.end {
+            return None;
+        }
+        Some(access.addr.max(self.start))
+    }
+}
+
+impl fmt::Display for Watch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let kind = match (self.read, self.write) {
+            (true, true) => "rw",
+            (true, false) => "r",
+            (false, true) => "w",
+            (false, false) => "-",
+        };
+        if self.start == self.end {
+            write!(f, "{:03X} {}", self.start, kind)
+        } else {
+            write!(f, "{:03X}-{:03X} {}", self.start, self.end, kind)
+        }
+    }
+}
+
+/// A register that can be watched for changes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Register {
+    V(u8),
+    I,
+    Dt,
+    St,
+}
+
+impl Register {
+    /** Parses `V0`-`VF`, `I`, `DT` or `ST`, ignoring case */
+    pub fn from_name(name: &str) -> Option<Register> {
+        match name.to_lowercase().as_str() {
+            "i" => Some(Register::I),
+            "dt" => Some(Register::Dt),
+            "st" => Some(Register::St),
+            reg if reg.len() == 2 && reg.starts_with('v') => {
+                u8::from_str_radix(&reg[1..], 16).ok().map(Register::V)
+            },
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Register {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Register::V(x) => write!(f, "V{:X}", x),
+            Register::I => write!(f, "I"),
+            Register::Dt => write!(f, "DT"),
+            Register::St => write!(f, "ST"),
+        }
+    }
+}
+
+/// Why `Chip8::run_until` returned.
+#[derive(Debug, Clone, PartialEq)]
+pub enum StopReason {
+    /// The instruction limit was reached.
+    Limit,
+    /// An instruction waited for the vertical blank (`Quirks::display_wait`),
+    /// ending the frame.
+    VBlank,
+    /// PC reached a breakpoint; the instruction there has not run yet.
+    Breakpoint(u16),
+    /// The instruction at `pc` made a watched access to `addr`.
+    Memory { pc: u16, addr: u16, access: Access },
+    /// The instruction at `pc` changed a watched register.
+    Register { pc: u16, register: Register, old: u16, new: u16 },
+    /// An instruction faulted and halted the machine (`FaultPolicy::Halt`).
+    Halted(Chip8Error),
+    /// An instruction faulted and was not executed (`FaultPolicy::Trap`).
+    Trapped(Chip8Error),
+}
+
+impl fmt::Display for StopReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            StopReason::Limit => write!(f, "instruction limit reached"),
+            StopReason::VBlank => write!(f, "waiting for vertical blank"),
+            StopReason::Breakpoint(pc) => write!(f, "breakpoint at {:03X}", pc),
+            StopReason::Memory { pc, addr, access } => write!(f, "{} of {:03X} at {:03X}", access, addr, pc),
+            StopReason::Register { pc, register, old, new } => {
+                write!(f, "{} changed from {:X} to {:X} at {:03X}", register, old, new, pc)
+            },
+            StopReason::Halted(ref e) => write!(f, "halted: {}", e),
+            StopReason::Trapped(ref e) => write!(f, "trapped: {}", e),
+        }
+    }
+}
+
+/// The breakpoints and watchpoints a `Chip8` stops at in `run_until`.
+#[derive(Debug, Clone, Default)]
+pub struct Breakpoints {
+    pc: BTreeSet<u16>,
+    watches: Vec<Watch>,
+    registers: BTreeSet<Register>,
+}
+
+impl Breakpoints {
+    /** Adds a breakpoint at `addr`; false if there already was one */
+    pub fn add(&mut self, addr: u16) -> bool {
+        self.pc.insert(addr)
+    }
+
+    /** Removes the breakpoint at `addr`; false if there was none */
+    pub fn remove(&mut self, addr: u16) -> bool {
+        self.pc.remove(&addr)
+    }
+
+    pub fn contains(&self, addr: u16) -> bool {
+        self.pc.contains(&addr)
+    }
+
+    /** Breakpoint addresses in ascending order */
+    pub fn addresses<'a>(&'a self) -> impl Iterator<Item = u16> + 'a {
+        self.pc.iter().cloned()
+    }
+
+    pub fn clear_addresses(&mut self) {
+        self.pc.clear();
+    }
+
+    /** Adds a memory watchpoint */
+    pub fn watch(&mut self, watch: Watch) {
+        self.watches.push(watch);
+    }
+
+    /** Removes the memory watchpoint at `index` in `watches` */
+    pub fn unwatch(&mut self, index: usize) -> Option<Watch> {
+        if index < self.watches.len() {
+            Some(self.watches.remove(index))
+        } else {
+            None
+        }
+    }
+
+    pub fn watches(&self) -> &[Watch] {
+        &self.watches
+    }
+
+    /** Stops whenever an instruction changes `register`; false if it was already watched */
+    pub fn watch_register(&mut self, register: Register) -> bool {
+        self.registers.insert(register)
+    }
+
+    pub fn unwatch_register(&mut self, register: Register) -> bool {
+        self.registers.remove(&register)
+    }
+
+    pub fn registers<'a>(&'a self) -> impl Iterator<Item = Register> + 'a {
+        self.registers.iter().cloned()
+    }
+
+    /** Removes every memory and register watchpoint, leaving breakpoints */
+    pub fn clear_watches(&mut self) {
+        self.watches.clear();
+        self.registers.clear();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pc.is_empty() && self.watches.is_empty() && self.registers.is_empty()
+    }
+
+    /** The first watchpoint `access` hits, as the address it touched */
+    pub fn memory_hit(&self, access: &MemoryAccess) -> Option<u16> {
+        self.watches.iter().filter_map(|watch| watch.hit(access)).min()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read(addr: u16, len: u16) -> MemoryAccess {
+        MemoryAccess { addr, len, access: Access::Read }
+    }
+
+    fn write(addr: u16, len: u16) -> MemoryAccess {
+        MemoryAccess { addr, len, access: Access::Write }
+    }
+
+    #[test]
+    fn watch_directions() {
+        let read_only = Watch { start: 0x300, end: 0x300, read: true, write: false };
+        let write_only = Watch { start: 0x300, end: 0x300, read: false, write: true };
+        let both = Watch { start: 0x300, end: 0x300, read: true, write: true };
+        assert_eq!(read_only.hit(&read(0x300, 1)), Some(0x300));
+        assert_eq!(read_only.hit(&write(0x300, 1)), None);
+        assert_eq!(write_only.hit(&read(0x300, 1)), None);
+        assert_eq!(write_only.hit(&write(0x300, 1)), Some(0x300));
+        assert_eq!(both.hit(&read(0x300, 1)), Some(0x300));
+        assert_eq!(both.hit(&write(0x300, 1)), Some(0x300));
+    }
+
+    #[test]
+    fn watch_overlap() {
+        let watch = Watch { start: 0x300, end: 0x30F, read: true, write: true };
+        // Ending just before, or starting just after, the range
+        assert_eq!(watch.hit(&read(0x2FD, 3)), None);
+        assert_eq!(watch.hit(&read(0x310, 5)), None);
+        // Overlapping the first or last byte
+        assert_eq!(watch.hit(&read(0x2FE, 3)), Some(0x300));
+        assert_eq!(watch.hit(&write(0x30F, 4)), Some(0x30F));
+        // Inside, and covering the whole range
+        assert_eq!(watch.hit(&write(0x304, 2)), Some(0x304));
+        assert_eq!(watch.hit(&read(0x200, 0x200)), Some(0x300));
+    }
+
+    #[test]
+    fn breakpoint_bookkeeping() {
+        let mut breakpoints = Breakpoints::default();
+        assert!(breakpoints.is_empty());
+        assert!(breakpoints.add(0x210));
+        assert!(breakpoints.add(0x204));
+        assert!(!breakpoints.add(0x210));
+        assert!(breakpoints.contains(0x204));
+        assert_eq!(breakpoints.addresses().collect::<Vec<_>>(), vec![0x204, 0x210]);
+        assert!(breakpoints.remove(0x204));
+        assert!(!breakpoints.remove(0x204));
+
+        breakpoints.watch(Watch { start: 0x310, end: 0x31F, read: true, write: false });
+        breakpoints.watch(Watch { start: 0x300, end: 0x30F, read: true, write: false });
+        // The lowest address touched in any watched range
+        assert_eq!(breakpoints.memory_hit(&read(0x30E, 4)), Some(0x30E));
+        assert_eq!(breakpoints.memory_hit(&write(0x30E, 4)), None);
+        assert_eq!(breakpoints.unwatch(0).map(|watch| watch.start), Some(0x310));
+        assert_eq!(breakpoints.unwatch(1), None);
+
+        assert!(breakpoints.watch_register(Register::V(3)));
+        assert!(!breakpoints.watch_register(Register::V(3)));
+        breakpoints.clear_watches();
+        assert!(breakpoints.watches().is_empty());
+        assert_eq!(breakpoints.registers().count(), 0);
+        assert!(!breakpoints.is_empty(), "breakpoints survive clear_watches");
+        breakpoints.clear_addresses();
+        assert!(breakpoints.is_empty());
+    }
+
+    #[test]
+    fn empty_access_hits_nothing() {
+        let watch = Watch { start: 0x300, end: 0x300, read: true, write: true };
+        assert_eq!(watch.hit(&MemoryAccess { addr: 0x300, len: 0, access: Access::Read }), None);
+        assert_eq!(watch.hit(&MemoryAccess { addr: 0, len: 0, access: Access::Read }), None);
+    }
+}
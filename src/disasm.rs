@@ -0,0 +1,275 @@
+/*  disasm.rs
+ *  Control-flow-aware disassembler. Starting from the entry point, follows
+ *  jumps, calls and skips to work out which bytes are executed; everything
+ *  else is treated as data and rendered as sprite rows. Listings can be
+ *  written in classic (Cowgod) mnemonics or Octo syntax; the Octo listing
+ *  reassembles with chip8asm to the original bytes. */
+
+use std::collections::BTreeMap;
+use std::io;
+use std::io::prelude::*;
+
+use chip8::PROGRAM_START;
+use instruction::Instruction;
+
+/// Output flavor for `Disassembly::write_listing`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Syntax {
+    /// Cowgod-style mnemonics, e.g. `DRW V3, V4, 5`
+    Classic,
+    /// Octo assembly, e.g. `sprite v3 v4 5`
+    Octo,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum LabelKind {
+    Jump,
+    Call,
+    Data,
+}
+
+/// The result of analyzing a ROM: which addresses hold reachable instructions
+/// and which addresses are referenced by them.
+pub struct Disassembly {
+    origin: u16,
+    bytes: Vec<u8>,
+    /// `code[n]` is set when an instruction starting at `origin + n` is reachable
+    code: Vec<bool>,
+    labels: BTreeMap<u16, LabelKind>,
+}
+
+impl Disassembly {
+    /** Analyzes `rom` as loaded at `PROGRAM_START` */
+    pub fn analyze(rom: &[u8]) -> Disassembly {
+        Disassembly::analyze_at(rom, PROGRAM_START)
+    }
+
+    /** Analyzes `rom` as loaded at `origin`, with execution starting at `origin` */
+    pub fn analyze_at(rom: &[u8], origin: u16) -> Disassembly {
+        let mut dis = Disassembly {
+            origin,
+            bytes: rom.to_vec(),
+            code: vec![false; rom.len()],
+            labels: BTreeMap::new(),
+        };
+
+        let mut pending = vec![origin];
+        while let Some(addr) = pending.pop() {
+            let instr = match dis.decode_at(addr) {
+                Some(instr) => instr,
+                None => continue,
+            };
+            let offset = (addr - origin) as usize;
+            if dis.code[offset] {
+                continue;
+            }
+            dis.code[offset] = true;
+
+            let next = addr + 2;
+            match instr {
+                Instruction::Ret => {},
+                Instruction::Jp(nnn) => {
+                    dis.labels.insert(nnn, LabelKind::Jump);
+                    pending.push(nnn);
+                },
+                Instruction::JpV0(nnn) => {
+                    // Jump tables: only the V0 == 0 entry can be followed statically
+                    dis.labels.insert(nnn, LabelKind::Jump);
+                    pending.push(nnn);
+                },
+                Instruction::Call(nnn) => {
+                    dis.labels.insert(nnn, LabelKind::Call);
+                    pending.push(nnn);
+                    pending.push(next);
+                },
+                Instruction::SeVxByte { .. } | Instruction::SneVxByte { .. } |
+                Instruction::SeVxVy { .. } | Instruction::SneVxVy { .. } |
+                Instruction::Skp { .. } | Instruction::Sknp { .. } => {
+                    pending.push(next);
+                    pending.push(next + 2);
+                },
+                Instruction::LdI(nnn) => {
+                    dis.labels.entry(nnn).or_insert(LabelKind::Data);
+                    pending.push(next);
+                },
+                _ => pending.push(next),
+            }
+        }
+
+        dis
+    }
+
+    /** Decodes the instruction at `addr`, if both of its bytes are in the ROM.
+     *  `0NNN` machine code calls are treated as data. */
+    fn decode_at(&self, addr: u16) -> Option<Instruction> {
+        if addr < self.origin {
+            return None;
+        }
+        let offset = (addr - self.origin) as usize;
+        if offset + 1 >= self.bytes.len() {
+            return None;
+        }
+        match Instruction::decode((self.bytes[offset] as u16) << 8 | self.bytes[offset + 1] as u16) {
+            Some(Instruction::Sys(_)) | None => None,
+            instr => instr,
+        }
+    }
+
+    /** Whether a reachable instruction starts at `addr` */
+    pub fn is_code(&self, addr: u16) -> bool {
+        addr >= self.origin && self.code.get((addr - self.origin) as usize).cloned().unwrap_or(false)
+    }
+
+    /** Splits the ROM into listing lines: `(addr, Some(instr))` for code and
+     *  `(addr, None)` for a single data byte. Instructions overlapping the
+     *  previous one are emitted as data so every byte appears exactly once. */
+    fn items(&self) -> Vec<(u16, Option<Instruction>)> {
+        let mut items = Vec::new();
+        let mut offset = 0;
+        while offset < self.bytes.len() {
+            let addr = self.origin + offset as u16;
+            if self.code[offset] {
+                if let Some(instr) = self.decode_at(addr) {
+                    items.push((addr, Some(instr)));
+                    offset += 2;
+                    continue;
+                }
+            }
+            items.push((addr, None));
+            offset += 1;
+        }
+        items
+    }
+
+    /** Writes a labelled listing of the whole ROM */
+    pub fn write_listing<W: Write>(&self, out: &mut W, syntax: Syntax) -> io::Result<()> {
+        let items = self.items();
+
+        // Only addresses that start a listing line can carry a label; anything
+        // else (targets outside the ROM or inside an instruction) stays numeric.
+        let mut names = BTreeMap::new();
+        for &(addr, _) in items.iter() {
+            if let Some(kind) = self.labels.get(&addr) {
+                let prefix = match *kind {
+                    LabelKind::Jump => "L",
+                    LabelKind::Call => "sub_",
+                    LabelKind::Data => "data_",
+                };
+                names.insert(addr, format!("{}{:03X}", prefix, addr));
+            }
+        }
+
+        let comment = match syntax {
+            Syntax::Classic => ";",
+            Syntax::Octo => "#",
+        };
+        writeln!(out, "{} {} bytes at 0x{:03X}", comment, self.bytes.len(), self.origin)?;
+
+        let mut in_data = false;
+        for &(addr, instr) in items.iter() {
+            if let Some(name) = names.get(&addr) {
+                writeln!(out)?;
+                match syntax {
+                    Syntax::Classic => writeln!(out, "{}:", name)?,
+                    Syntax::Octo => writeln!(out, ": {}", name)?,
+                }
+            } else if in_data != instr.is_none() {
+                writeln!(out)?;
+            }
+
+            let offset = (addr - self.origin) as usize;
+            match instr {
+                Some(instr) => {
+                    in_data = false;
+                    let text = match syntax {
+                        Syntax::Classic => classic(instr, &names),
+                        Syntax::Octo => octo(instr, &names),
+                    };
+                    writeln!(out, "    {:<24}{} {:03X}: {:02X}{:02X}", text, comment, addr,
+                             self.bytes[offset], self.bytes[offset + 1])?;
+                },
+                None => {
+                    in_data = true;
+                    let byte = self.bytes[offset];
+                    let text = match syntax {
+                        Syntax::Classic => format!("DB 0x{:02X}", byte),
+                        Syntax::Octo => format!("0x{:02X}", byte),
+                    };
+                    writeln!(out, "    {:<24}{} {:03X}: {}", text, comment, addr, sprite_row(byte))?;
+                },
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/** Renders a data byte as an 8-pixel sprite row */
+fn sprite_row(byte: u8) -> String {
+    (0..8).map(|bit| if byte & (0x80 >> bit) != 0 { '#' } else { '.' }).collect()
+}
+
+fn target(addr: u16, names: &BTreeMap<u16, String>) -> String {
+    match names.get(&addr) {
+        Some(name) => name.clone(),
+        None => format!("0x{:03X}", addr),
+    }
+}
+
+/** Classic mnemonic, with addresses replaced by label names where possible */
+fn classic(instr: Instruction, names: &BTreeMap<u16, String>) -> String {
+    match instr {
+        Instruction::Jp(nnn) => format!("JP {}", target(nnn, names)),
+        Instruction::Call(nnn) => format!("CALL {}", target(nnn, names)),
+        Instruction::LdI(nnn) => format!("LD I, {}", target(nnn, names)),
+        Instruction::JpV0(nnn) => format!("JP V0, {}", target(nnn, names)),
+        _ => instr.to_string(),
+    }
+}
+
+/** Octo statement for `instr`. Octo's `if ... then` names the condition under
+ *  which the next statement runs, so it is the inverse of the skip condition. */
+fn octo(instr: Instruction, names: &BTreeMap<u16, String>) -> String {
+    use instruction::Instruction::*;
+
+    match instr {
+        Sys(nnn) => format!("0x{:02X} 0x{:02X}", nnn >> 8, nnn & 0xFF),
+        Cls => "clear".to_string(),
+        Ret => "return".to_string(),
+        Jp(nnn) => format!("jump {}", target(nnn, names)),
+        Call(nnn) => match names.get(&nnn) {
+            Some(name) => name.clone(),
+            None => format!(":call 0x{:03X}", nnn),
+        },
+        SeVxByte { x, nn } => format!("if v{:x} != 0x{:02X} then", x, nn),
+        SneVxByte { x, nn } => format!("if v{:x} == 0x{:02X} then", x, nn),
+        SeVxVy { x, y } => format!("if v{:x} != v{:x} then", x, y),
+        LdVxByte { x, nn } => format!("v{:x} := 0x{:02X}", x, nn),
+        AddVxByte { x, nn } => format!("v{:x} += 0x{:02X}", x, nn),
+        LdVxVy { x, y } => format!("v{:x} := v{:x}", x, y),
+        Or { x, y } => format!("v{:x} |= v{:x}", x, y),
+        And { x, y } => format!("v{:x} &= v{:x}", x, y),
+        Xor { x, y } => format!("v{:x} ^= v{:x}", x, y),
+        AddVxVy { x, y } => format!("v{:x} += v{:x}", x, y),
+        Sub { x, y } => format!("v{:x} -= v{:x}", x, y),
+        Shr { x, y } => format!("v{:x} >>= v{:x}", x, y),
+        Subn { x, y } => format!("v{:x} =- v{:x}", x, y),
+        Shl { x, y } => format!("v{:x} <<= v{:x}", x, y),
+        SneVxVy { x, y } => format!("if v{:x} == v{:x} then", x, y),
+        LdI(nnn) => format!("i := {}", target(nnn, names)),
+        JpV0(nnn) => format!("jump0 {}", target(nnn, names)),
+        Rnd { x, nn } => format!("v{:x} := random 0x{:02X}", x, nn),
+        Drw { x, y, n } => format!("sprite v{:x} v{:x} {}", x, y, n),
+        Skp { x } => format!("if v{:x} -key then", x),
+        Sknp { x } => format!("if v{:x} key then", x),
+        LdVxDt { x } => format!("v{:x} := delay", x),
+        LdVxK { x } => format!("v{:x} := key", x),
+        LdDtVx { x } => format!("delay := v{:x}", x),
+        LdStVx { x } => format!("buzzer := v{:x}", x),
+        AddIVx { x } => format!("i += v{:x}", x),
+        LdFVx { x } => format!("i := hex v{:x}", x),
+        LdBVx { x } => format!("bcd v{:x}", x),
+        LdIVx { x } => format!("save v{:x}", x),
+        LdVxI { x } => format!("load v{:x}", x),
+    }
+}
@@ -0,0 +1,136 @@
+/*  error.rs
+ *  Error types returned by the chip8remu core */
+
+use std::error;
+use std::fmt;
+use std::io;
+
+use chip8::MAX_ROM_SIZE;
+use state::STATE_VERSION;
+
+/// A fault raised while executing an instruction. `pc` is the address of the
+/// faulting instruction.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Chip8Error {
+    /// The word at `pc` does not decode to any known instruction.
+    InvalidOpcode { pc: u16, opcode: u16 },
+    /// `2NNN` was executed with all 16 stack slots in use.
+    StackOverflow { pc: u16 },
+    /// `00EE` was executed with an empty stack.
+    StackUnderflow { pc: u16 },
+    /// The instruction (or its fetch) touched `addr`, which lies past the end of memory.
+    MemoryOutOfBounds { pc: u16, addr: usize },
+}
+
+impl fmt::Display for Chip8Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Chip8Error::InvalidOpcode { pc, opcode } => write!(f, "invalid opcode {:04X} at {:03X}", opcode, pc),
+            Chip8Error::StackOverflow { pc } => write!(f, "stack overflow at {:03X}", pc),
+            Chip8Error::StackUnderflow { pc } => write!(f, "return with empty stack at {:03X}", pc),
+            Chip8Error::MemoryOutOfBounds { pc, addr } => write!(f, "memory access out of bounds ({:X}) at {:03X}", addr, pc),
+        }
+    }
+}
+
+impl error::Error for Chip8Error {}
+
+/// How a `Chip8` reacts when an instruction faults.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum FaultPolicy {
+    /// Stop the machine: the fault is returned as an `Err`, now and on every
+    /// later cycle.
+    #[default]
+    Halt,
+    /// Skip the faulting instruction as if it were a no-op and keep running.
+    Nop,
+    /// Leave the machine on the faulting instruction and report it as
+    /// `StepOutcome::Trapped`, so a debugger can inspect it.
+    Trap,
+}
+
+/// What happened during one `emulate_cycle`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum StepOutcome {
+    /// The instruction ran normally.
+    Executed,
+    /// The instruction faulted and was skipped (`FaultPolicy::Nop`).
+    Skipped(Chip8Error),
+    /// The instruction faulted and was not executed (`FaultPolicy::Trap`).
+    Trapped(Chip8Error),
+}
+
+/// Why a ROM could not be loaded into memory.
+#[derive(Debug)]
+pub enum RomError {
+    /// The ROM file could not be opened or read.
+    Io(io::Error),
+    /// The ROM (of the given size in bytes) does not fit above `PROGRAM_START`.
+    TooLarge(usize),
+}
+
+impl fmt::Display for RomError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            RomError::Io(ref e) => write!(f, "could not read ROM: {}", e),
+            RomError::TooLarge(size) => write!(f, "ROM is {} bytes, but at most {} bytes fit in memory", size, MAX_ROM_SIZE),
+        }
+    }
+}
+
+impl error::Error for RomError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            RomError::Io(ref e) => Some(e),
+            RomError::TooLarge(_) => None,
+        }
+    }
+}
+
+impl From<io::Error> for RomError {
+    fn from(e: io::Error) -> RomError {
+        RomError::Io(e)
+    }
+}
+
+/// Why a save state could not be read or restored.
+#[derive(Debug)]
+pub enum StateError {
+    /// The state file could not be read.
+    Io(io::Error),
+    /// The data does not start with the save state magic number.
+    NotAState,
+    /// The state was written in a format version this build cannot read.
+    Version(u16),
+    /// The state is damaged; the message says which field was invalid.
+    Corrupt(&'static str),
+    /// The state was saved while a different ROM was loaded.
+    RomMismatch,
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            StateError::Io(ref e) => write!(f, "could not read save state: {}", e),
+            StateError::NotAState => write!(f, "not a save state"),
+            StateError::Version(version) => write!(f, "save state is format version {}, but this build reads version {}", version, STATE_VERSION),
+            StateError::Corrupt(what) => write!(f, "save state is corrupt: {}", what),
+            StateError::RomMismatch => write!(f, "save state belongs to a different ROM"),
+        }
+    }
+}
+
+impl error::Error for StateError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            StateError::Io(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for StateError {
+    fn from(e: io::Error) -> StateError {
+        StateError::Io(e)
+    }
+}
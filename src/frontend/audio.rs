@@ -0,0 +1,96 @@
+/*  audio.rs
+ *  Square-wave beeper for the CHIP-8 sound timer */
+
+use sdl2::AudioSubsystem;
+use sdl2::audio::{AudioCallback, AudioDevice, AudioSpecDesired};
+
+/// Default tone, in Hz.
+pub const DEFAULT_PITCH: f32 = 440.0;
+/// Default volume, from 0.0 (silent) to 1.0 (full scale).
+pub const DEFAULT_VOLUME: f32 = 0.25;
+
+/// Seconds the beeper takes to fade in or out; long enough to avoid the click
+/// of a hard edge, short enough to be inaudible as a fade.
+const RAMP_SECONDS: f32 = 0.005;
+
+struct SquareWave {
+    phase: f32,
+    phase_inc: f32,
+    volume: f32,
+    /// Current output level, eased towards `volume` or 0 by `ramp_step` per sample
+    level: f32,
+    ramp_step: f32,
+    gate: bool,
+    muted: bool,
+}
+
+impl AudioCallback for SquareWave {
+    type Channel = f32;
+
+    fn callback(&mut self, out: &mut [f32]) {
+        let target = if self.gate && !self.muted { self.volume } else { 0.0 };
+        for sample in out.iter_mut() {
+            if self.level < target {
+                self.level = (self.level + self.ramp_step).min(target);
+            } else if self.level > target {
+                self.level = (self.level - self.ramp_step).max(target);
+            }
+
+            *sample = if self.phase < 0.5 { self.level } else { -self.level };
+            // The oscillator keeps running while silent so restarts are phase-continuous
+            self.phase = (self.phase + self.phase_inc) % 1.0;
+        }
+    }
+}
+
+/// An open SDL audio device that plays a tone while the sound timer runs.
+pub struct Beeper {
+    device: AudioDevice<SquareWave>,
+}
+
+impl Beeper {
+    /** Opens the default playback device with the given tone (Hz) and volume (0.0-1.0) */
+    pub fn new(audio: &AudioSubsystem, pitch: f32, volume: f32) -> Result<Beeper, String> {
+        let desired = AudioSpecDesired {
+            freq: Some(44100),
+            channels: Some(1),
+            samples: None,
+        };
+
+        let device = audio.open_playback(None, &desired, |spec| {
+            let rate = spec.freq as f32;
+            let volume = volume.clamp(0.0, 1.0);
+            SquareWave {
+                phase: 0.0,
+                phase_inc: pitch / rate,
+                volume,
+                level: 0.0,
+                ramp_step: volume / (RAMP_SECONDS * rate),
+                gate: false,
+                muted: false,
+            }
+        })?;
+        device.resume();
+
+        Ok(Beeper { device })
+    }
+
+    /** Starts or stops the tone; call once per frame with `Chip8::sound_active` */
+    pub fn set_active(&mut self, active: bool) {
+        let mut wave = self.device.lock();
+        wave.gate = active;
+    }
+
+    /** Silences or unsilences the tone without affecting the sound timer */
+    pub fn set_muted(&mut self, muted: bool) {
+        let mut wave = self.device.lock();
+        wave.muted = muted;
+    }
+
+    /** Flips the mute switch, returning whether the beeper is now muted */
+    pub fn toggle_mute(&mut self) -> bool {
+        let mut wave = self.device.lock();
+        wave.muted = !wave.muted;
+        wave.muted
+    }
+}
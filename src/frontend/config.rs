@@ -0,0 +1,145 @@
+/*  config.rs
+ *  The user's config file: global defaults plus per-ROM overrides, keyed by
+ *  the SHA-1 of the ROM so they follow the game regardless of its file name.
+ *
+ *  ```toml
+ *  [defaults]
+ *  scale = 12
+ *  palette = "amber"
+ *
+ *  [rom.0d0d6d0fd1d3e8e5d6d8bfa7f5a4e6a2b6ee9c4f]
+ *  name = "PONG"
+ *  ipf = 15
+ *  quirks = "schip"
+ *  keys = "1=Up,4=Down"
+ *  ```
+ */
+
+use std::collections::BTreeMap;
+use std::env;
+use std::fs::{self, File};
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+
+use toml;
+
+use chip8remu::state;
+
+/// Settings that can come from the config file or the command line. Unset
+/// fields fall through to the next layer: built-in defaults, then
+/// `[defaults]`, then the ROM's section, then the command line.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Settings {
+    /// The ROM's file name when the section was written; informational only
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub scale: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ipf: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub quirks: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub palette: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub persistence: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rewind: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub keys: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pitch: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub volume: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mute: Option<bool>,
+}
+
+impl Settings {
+    /** Overwrites every field that is set in `other` */
+    pub fn merge(&mut self, other: &Settings) {
+        fn take<T: Clone>(field: &mut Option<T>, other: &Option<T>) {
+            if other.is_some() {
+                *field = other.clone();
+            }
+        }
+        take(&mut self.name, &other.name);
+        take(&mut self.scale, &other.scale);
+        take(&mut self.ipf, &other.ipf);
+        take(&mut self.quirks, &other.quirks);
+        take(&mut self.palette, &other.palette);
+        take(&mut self.persistence, &other.persistence);
+        take(&mut self.rewind, &other.rewind);
+        take(&mut self.keys, &other.keys);
+        take(&mut self.pitch, &other.pitch);
+        take(&mut self.volume, &other.volume);
+        take(&mut self.mute, &other.mute);
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Config {
+    #[serde(default)]
+    pub defaults: Settings,
+    /// Per-ROM sections, keyed by `rom_key`
+    #[serde(default)]
+    pub rom: BTreeMap<String, Settings>,
+}
+
+impl Config {
+    /** Reads the config at `path`. A missing file is an empty config. */
+    pub fn load(path: &Path) -> Result<Config, String> {
+        let mut text = String::new();
+        match File::open(path) {
+            Ok(mut file) => {
+                file.read_to_string(&mut text).map_err(|e| format!("{}: {}", path.display(), e))?;
+            },
+            Err(_) if !path.exists() => return Ok(Config::default()),
+            Err(e) => return Err(format!("{}: {}", path.display(), e)),
+        }
+        toml::from_str(&text).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    /** Writes the config to `path`, creating its directory if needed */
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        let text = toml::to_string(self).map_err(|e| e.to_string())?;
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).map_err(|e| format!("{}: {}", dir.display(), e))?;
+        }
+        File::create(path)
+            .and_then(|mut file| file.write_all(text.as_bytes()))
+            .map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    /** Merges `settings` into the section for ROM `key` of the config file at
+     *  `path`, re-reading the file first so edits made since startup are kept */
+    pub fn save_rom_settings(path: &Path, key: &str, settings: &Settings) -> Result<(), String> {
+        let mut config = Config::load(path)?;
+        config.rom.entry(key.to_string()).or_insert_with(Settings::default).merge(settings);
+        config.save(path)
+    }
+
+    /** The effective settings for the ROM with key `key`, before command-line overrides */
+    pub fn settings_for(&self, key: &str) -> Settings {
+        let mut settings = self.defaults.clone();
+        if let Some(section) = self.rom.get(key) {
+            settings.merge(section);
+        }
+        settings
+    }
+}
+
+/** Identifies a ROM by the SHA-1 of its contents, as lowercase hex */
+pub fn rom_key(rom: &[u8]) -> String {
+    state::rom_hash(rom).iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/** Where the config lives when `--config` is not given:
+ *  `$XDG_CONFIG_HOME/chip8remu/config.toml`, falling back to `~/.config` or,
+ *  on Windows, `%APPDATA%` */
+pub fn default_path() -> Option<PathBuf> {
+    let base = env::var_os("XDG_CONFIG_HOME").map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".config")))
+        .or_else(|| env::var_os("APPDATA").map(PathBuf::from));
+    base.map(|dir| dir.join("chip8remu").join("config.toml"))
+}
@@ -0,0 +1,38 @@
+/*  dap.rs
+ *  `--dap`: serves a Debug Adapter Protocol session (see `chip8remu::dap`) on
+ *  stdin and stdout. Nothing else may be written to stdout while it runs. */
+
+use std::io::{self, BufReader};
+use std::path::PathBuf;
+
+use chip8remu::Chip8;
+use chip8remu::dap;
+
+use frontend::config::{self, Config, Settings};
+use frontend::options::{Args, Options};
+
+/** Serves one debug session until the client disconnects. `args` are the
+ *  other options given with `--dap`; they set up the machine like a normal
+ *  run, and the launch request can override them. */
+pub fn serve(args: &Args) -> Result<(), String> {
+    let config_path = args.config_path.as_ref().map(PathBuf::from).or_else(config::default_path);
+    let stdout = io::stdout();
+    dap::serve_on(BufReader::new(io::stdin()), stdout.lock(), |rom| configure(rom, args, &config_path))
+}
+
+/** The machine a launched ROM runs on, set up from the command line and the
+ *  ROM's section of the config file like a normal run */
+fn configure(rom: &[u8], args: &Args, config_path: &Option<PathBuf>) -> Result<Chip8, String> {
+    let mut settings = match *config_path {
+        Some(ref path) => Config::load(path)?.settings_for(&config::rom_key(rom)),
+        None => Settings::default(),
+    };
+    settings.merge(&args.settings);
+    let options = Options::resolve(&settings)?;
+    let mut core = Chip8::with_quirks(options.quirks);
+    core.ipf = options.ipf;
+    if let Some(seed) = args.seed {
+        core.seed_rng(seed);
+    }
+    Ok(core)
+}
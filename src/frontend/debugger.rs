@@ -0,0 +1,458 @@
+/*  debugger.rs
+ *  Interactive debugger console for `--debug`. Commands are read from stdin
+ *  on a background thread so the window keeps drawing and responding while
+ *  the prompt waits; the main loop hands them to `Debugger::poll` once per
+ *  frame. All numbers are printed in hex; arguments may be given in hex with
+ *  a `0x` prefix or in decimal. */
+
+use std::fs::File;
+use std::io::{self, BufRead, Write};
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread;
+
+use chip8remu::{Chip8, Chip8Error, Instruction, Register, StepOutcome, StopReason, Watch};
+use chip8remu::chip8::MEMORY_SIZE;
+use chip8remu::number;
+
+const PROMPT: &str = "(c8db) ";
+
+const HELP: &str = "\
+Commands (numbers are decimal, or hex with a 0x prefix):
+    step [n]            execute n instructions (default 1)          (s)
+    continue            run until a breakpoint or fault             (c)
+    pause               stop running
+    break [addr]        set a breakpoint at addr, or list them      (b)
+    delete [addr]       delete the breakpoint at addr, or all of them
+    watch [reg]         stop when an instruction changes V0-VF, I, DT or ST,
+                        or list watchpoints
+    watch <addr> [len]  stop after an instruction writes to the range
+    rwatch <addr> [len] stop after an instruction reads from the range
+    awatch <addr> [len] stop after an instruction reads or writes the range
+    unwatch [n|reg]     delete memory watchpoint n or a register watchpoint,
+                        or all of them
+    regs                show registers                              (r)
+    mem <addr> [len]    hex dump len bytes (default 64)             (x)
+    dis [addr] [n]      disassemble n instructions (default 10) from addr,
+                        or from PC
+    stack               show the call stack
+    set <reg> <value>   set V0-VF, I, DT, ST, PC or SP
+    poke <addr> <byte>...  write bytes to memory
+    dump <file>         write all 4 KiB of memory to file
+    help                show this text
+    quit                exit the emulator";
+
+/// What the main loop should do after `poll`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Action {
+    /// Keep running or stay paused, as the debugger's `paused` flag says
+    None,
+    Quit,
+}
+
+pub struct Debugger {
+    lines: Receiver<String>,
+    /// Whether execution is stopped at the prompt
+    pub paused: bool,
+}
+
+impl Debugger {
+    /** Starts reading commands from stdin. The machine starts paused. */
+    pub fn new() -> Debugger {
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            let stdin = io::stdin();
+            for line in stdin.lock().lines() {
+                match line {
+                    Ok(line) => if tx.send(line).is_err() { break },
+                    Err(_) => break,
+                }
+            }
+        });
+
+        println!("Debugger ready; type `help` for commands.");
+        prompt();
+        Debugger {
+            lines: rx,
+            paused: true,
+        }
+    }
+
+    /** Runs any commands typed since the last call */
+    pub fn poll(&mut self, core: &mut Chip8) -> Action {
+        loop {
+            match self.lines.try_recv() {
+                Ok(line) => {
+                    if self.command(core, line.trim()) == Action::Quit {
+                        return Action::Quit;
+                    }
+                    prompt();
+                },
+                Err(TryRecvError::Empty) => return Action::None,
+                // stdin closed: keep the window up, there is just no console
+                Err(TryRecvError::Disconnected) => return Action::None,
+            }
+        }
+    }
+
+    /** Stops at the prompt, e.g. because a fault or the pause key stopped the machine */
+    pub fn stop(&mut self, core: &Chip8) {
+        if !self.paused {
+            self.paused = true;
+            println!();
+            print_location(core);
+            prompt();
+        }
+    }
+
+    /** Leaves the prompt and runs, as `continue` does */
+    pub fn resume(&mut self, core: &mut Chip8) {
+        self.paused = false;
+        core.skip_breakpoint();
+    }
+
+    /** Like `Chip8::run_frame`, but honours the breakpoints and watchpoints
+     *  set on the core. Returns `None` if one of them stopped the frame,
+     *  leaving the debugger paused there. */
+    pub fn run_frame(&mut self, core: &mut Chip8) -> Option<Result<StepOutcome, Chip8Error>> {
+        match core.run_until(core.ipf) {
+            StopReason::Limit | StopReason::VBlank => {
+                core.tick_timers();
+                Some(Ok(StepOutcome::Executed))
+            },
+            StopReason::Halted(e) => Some(Err(e)),
+            StopReason::Trapped(e) => Some(Ok(StepOutcome::Trapped(e))),
+            reason => {
+                self.paused = true;
+                println!("\nStopped: {}", reason);
+                print_location(core);
+                prompt();
+                None
+            },
+        }
+    }
+
+    fn command(&mut self, core: &mut Chip8, line: &str) -> Action {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let (name, args) = match words.split_first() {
+            Some((name, args)) => (*name, args),
+            None => return Action::None,
+        };
+
+        let result = match name {
+            "s" | "step" => self.step(core, args),
+            "c" | "continue" => {
+                self.resume(core);
+                Ok(())
+            },
+            "pause" => {
+                self.paused = true;
+                print_location(core);
+                Ok(())
+            },
+            "b" | "break" => set_breakpoint(core, args),
+            "delete" => delete_breakpoint(core, args),
+            "watch" => watch(core, args, false, true),
+            "rwatch" => watch(core, args, true, false),
+            "awatch" => watch(core, args, true, true),
+            "unwatch" => unwatch(core, args),
+            "r" | "regs" => {
+                print_regs(core);
+                Ok(())
+            },
+            "x" | "mem" => mem(core, args),
+            "dis" => disassemble(core, args),
+            "stack" => {
+                print_stack(core);
+                Ok(())
+            },
+            "set" => set(core, args),
+            "poke" => poke(core, args),
+            "dump" => dump(core, args),
+            "help" | "?" => {
+                println!("{}", HELP);
+                Ok(())
+            },
+            "q" | "quit" => return Action::Quit,
+            _ => Err(format!("unknown command `{}`; type `help` for a list", name)),
+        };
+
+        if let Err(e) = result {
+            println!("error: {}", e);
+        }
+        Action::None
+    }
+
+    fn step(&mut self, core: &mut Chip8, args: &[&str]) -> Result<(), String> {
+        let count = match args.first() {
+            Some(n) => number(n)?,
+            None => 1,
+        };
+        self.paused = true;
+        for _ in 0..count {
+            print_instruction(core, core.pc, "");
+            match core.step() {
+                Ok(StepOutcome::Executed) => {},
+                Ok(StepOutcome::Skipped(e)) => println!("skipped: {}", e),
+                Ok(StepOutcome::Trapped(e)) | Err(e) => {
+                    println!("stopped: {}", e);
+                    break;
+                },
+            }
+        }
+        print_location(core);
+        Ok(())
+    }
+
+}
+
+fn prompt() {
+    print!("{}", PROMPT);
+    let _ = io::stdout().flush();
+}
+
+/** Parses a non-negative number, decimal unless prefixed */
+fn number(text: &str) -> Result<usize, String> {
+    match number::parse(text, 10) {
+        Some(value) if value >= 0 => Ok(value as usize),
+        _ => Err(format!("`{}` is not a number", text)),
+    }
+}
+
+fn address(text: &str) -> Result<u16, String> {
+    match number(text)? {
+        addr if addr < MEMORY_SIZE => Ok(addr as u16),
+        _ => Err(format!("{} is outside memory", text)),
+    }
+}
+
+fn byte(text: &str) -> Result<u8, String> {
+    match number(text)? {
+        value if value <= 0xFF => Ok(value as u8),
+        _ => Err(format!("{} does not fit in a byte", text)),
+    }
+}
+
+fn set_breakpoint(core: &mut Chip8, args: &[&str]) -> Result<(), String> {
+    match args.first() {
+        Some(addr) => {
+            let addr = address(addr)?;
+            core.breakpoints.add(addr);
+            println!("Breakpoint at {:03X}", addr);
+        },
+        None if core.breakpoints.addresses().next().is_none() => println!("No breakpoints"),
+        None => for addr in core.breakpoints.addresses() {
+            println!("  {:03X}", addr);
+        },
+    }
+    Ok(())
+}
+
+fn delete_breakpoint(core: &mut Chip8, args: &[&str]) -> Result<(), String> {
+    match args.first() {
+        Some(addr) => {
+            let addr = address(addr)?;
+            if !core.breakpoints.remove(addr) {
+                return Err(format!("no breakpoint at {:03X}", addr));
+            }
+        },
+        None => core.breakpoints.clear_addresses(),
+    }
+    Ok(())
+}
+
+fn disassemble(core: &Chip8, args: &[&str]) -> Result<(), String> {
+    let mut addr = match args.first() {
+        Some(addr) => address(addr)?,
+        None => core.pc,
+    };
+    let count = match args.get(1) {
+        Some(n) => number(n)?,
+        None => 10,
+    };
+    for _ in 0..count {
+        if core.fetch(addr).is_none() {
+            break;
+        }
+        let marker = match (addr == core.pc, core.breakpoints.contains(addr)) {
+            (true, true) => "*>",
+            (true, false) => " >",
+            (false, true) => "* ",
+            (false, false) => "  ",
+        };
+        print_instruction(core, addr, marker);
+        addr += 2;
+    }
+    Ok(())
+}
+
+fn watch(core: &mut Chip8, args: &[&str], read: bool, write: bool) -> Result<(), String> {
+    let first = match args.first() {
+        Some(first) => first,
+        None if read => return Err("usage: rwatch|awatch <addr> [len]".to_string()),
+        None => {
+            list_watches(core);
+            return Ok(());
+        },
+    };
+    if let Some(register) = Register::from_name(first) {
+        if read {
+            return Err("registers can only be watched for changes; use `watch`".to_string());
+        }
+        core.breakpoints.watch_register(register);
+        println!("Watching {}", register);
+        return Ok(());
+    }
+
+    let start = address(first)?;
+    let len = match args.get(1) {
+        Some(len) => number(len)?,
+        None => 1,
+    };
+    if len == 0 || len > MEMORY_SIZE - start as usize {
+        return Err("range must be non-empty and inside memory".to_string());
+    }
+    let watch = Watch { start, end: start + len as u16 - 1, read, write };
+    core.breakpoints.watch(watch);
+    println!("Watchpoint {}: {}", core.breakpoints.watches().len() - 1, watch);
+    Ok(())
+}
+
+fn unwatch(core: &mut Chip8, args: &[&str]) -> Result<(), String> {
+    let which = match args.first() {
+        Some(which) => which,
+        None => {
+            core.breakpoints.clear_watches();
+            return Ok(());
+        },
+    };
+    if let Some(register) = Register::from_name(which) {
+        if !core.breakpoints.unwatch_register(register) {
+            return Err(format!("{} is not watched", register));
+        }
+        return Ok(());
+    }
+    let index = number(which)?;
+    match core.breakpoints.unwatch(index) {
+        Some(_) => Ok(()),
+        None => Err(format!("no watchpoint {}", index)),
+    }
+}
+
+fn list_watches(core: &Chip8) {
+    let watches = core.breakpoints.watches();
+    let registers: Vec<String> = core.breakpoints.registers().map(|r| r.to_string()).collect();
+    if watches.is_empty() && registers.is_empty() {
+        println!("No watchpoints");
+    }
+    for (index, watch) in watches.iter().enumerate() {
+        println!("  {}: {}", index, watch);
+    }
+    if !registers.is_empty() {
+        println!("  registers: {}", registers.join(" "));
+    }
+}
+
+/** One listing line: `addr: word  mnemonic` */
+fn print_instruction(core: &Chip8, addr: u16, marker: &str) {
+    match core.fetch(addr) {
+        Some(word) => match Instruction::decode(word) {
+            Some(instr) => println!("{:2}{:03X}: {:04X}  {}", marker, addr, word, instr),
+            None => println!("{:2}{:03X}: {:04X}  ???", marker, addr, word),
+        },
+        None => println!("{:2}{:03X}: ----  (end of memory)", marker, addr),
+    }
+}
+
+/** The next instruction, for after a stop */
+pub fn print_location(core: &Chip8) {
+    print_instruction(core, core.pc, " >");
+}
+
+pub fn print_regs(core: &Chip8) {
+    for (half, regs) in core.v.chunks(8).enumerate() {
+        let cells: Vec<String> = regs.iter().enumerate()
+            .map(|(i, v)| format!("V{:X}={:02X}", half * 8 + i, v))
+            .collect();
+        println!("{}", cells.join(" "));
+    }
+    println!("I={:03X} DT={:02X} ST={:02X} PC={:03X} SP={:X}", core.i, core.dt, core.st, core.pc, core.sp);
+    print_location(core);
+}
+
+fn print_stack(core: &Chip8) {
+    if core.sp == 0 {
+        println!("Stack is empty");
+    }
+    // Innermost call first
+    let depth = (core.sp as usize).min(core.stack.len());
+    for (level, ret) in core.stack[..depth].iter().enumerate().rev() {
+        println!("  #{:<2} returns to {:03X}", level, ret);
+    }
+}
+
+fn mem(core: &Chip8, args: &[&str]) -> Result<(), String> {
+    let start = match args.first() {
+        Some(addr) => address(addr)? as usize,
+        None => return Err("usage: mem <addr> [len]".to_string()),
+    };
+    let len = match args.get(1) {
+        Some(len) => number(len)?,
+        None => 64.min(MEMORY_SIZE - start),
+    };
+    if len == 0 || len > MEMORY_SIZE - start {
+        return Err("range must be non-empty and inside memory".to_string());
+    }
+    let end = start + len;
+    for (row, bytes) in core.memory[start..end].chunks(16).enumerate() {
+        let hex: Vec<String> = bytes.iter().map(|b| format!("{:02X}", b)).collect();
+        println!("{:03X}: {}", start + row * 16, hex.join(" "));
+    }
+    Ok(())
+}
+
+fn set(core: &mut Chip8, args: &[&str]) -> Result<(), String> {
+    let (reg, value) = match *args {
+        [reg, value] => (reg.to_lowercase(), number(value)?),
+        _ => return Err("usage: set <reg> <value>".to_string()),
+    };
+    let fits = |max: usize| if value <= max { Ok(value) } else { Err(format!("{:X} is too large for {}", value, reg)) };
+
+    match reg.as_str() {
+        "i" => core.i = fits(0xFFFF)? as u16,
+        "dt" => core.dt = fits(0xFF)? as u8,
+        "st" => core.st = fits(0xFF)? as u8,
+        "pc" => core.pc = fits(MEMORY_SIZE - 1)? as u16,
+        "sp" => core.sp = fits(core.stack.len())? as u8,
+        _ if reg.len() == 2 && reg.starts_with('v') => match u8::from_str_radix(&reg[1..], 16) {
+            Ok(x) => core.v[x as usize] = fits(0xFF)? as u8,
+            Err(_) => return Err(format!("unknown register `{}`", reg)),
+        },
+        _ => return Err(format!("unknown register `{}`", reg)),
+    }
+    Ok(())
+}
+
+fn poke(core: &mut Chip8, args: &[&str]) -> Result<(), String> {
+    let (addr, bytes) = match args.split_first() {
+        Some((addr, bytes)) if !bytes.is_empty() => (address(addr)? as usize, bytes),
+        _ => return Err("usage: poke <addr> <byte>...".to_string()),
+    };
+    if addr + bytes.len() > MEMORY_SIZE {
+        return Err("write runs past the end of memory".to_string());
+    }
+    let bytes = bytes.iter().map(|text| byte(text)).collect::<Result<Vec<u8>, String>>()?;
+    core.memory[addr..addr + bytes.len()].copy_from_slice(&bytes);
+    Ok(())
+}
+
+fn dump(core: &Chip8, args: &[&str]) -> Result<(), String> {
+    let path = match args.first() {
+        Some(path) => path,
+        None => return Err("usage: dump <file>".to_string()),
+    };
+    File::create(path)
+        .and_then(|mut file| file.write_all(&core.memory))
+        .map_err(|e| format!("{}: {}", path, e))?;
+    println!("Wrote {} bytes to {}", MEMORY_SIZE, path);
+    Ok(())
+}
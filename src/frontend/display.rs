@@ -0,0 +1,93 @@
+/*  display.rs
+ *  Draws the CHIP-8 framebuffer: one streaming texture upload and one copy per
+ *  frame, scaled to the window with letterboxing */
+
+use sdl2::pixels::{Color, PixelFormatEnum};
+use sdl2::rect::Rect;
+use sdl2::render::{Canvas, Texture, TextureCreator};
+use sdl2::video::{FullscreenType, Window, WindowContext};
+
+use chip8remu::{DISPLAY_WIDTH, DISPLAY_HEIGHT};
+
+use frontend::palette::Palette;
+use frontend::persistence::Persistence;
+
+/// Default window size, in host pixels per CHIP-8 pixel.
+pub const DEFAULT_SCALE: u32 = 10;
+
+const BYTES_PER_PIXEL: usize = 3;
+
+pub struct Screen<'r> {
+    texture: Texture<'r>,
+    /// RGB24 staging buffer, uploaded to `texture` in one call
+    pixels: Vec<u8>,
+    pub palette: Palette,
+}
+
+impl<'r> Screen<'r> {
+    pub fn new(creator: &'r TextureCreator<WindowContext>, palette: Palette) -> Result<Screen<'r>, String> {
+        let texture = creator
+            .create_texture_streaming(PixelFormatEnum::RGB24, DISPLAY_WIDTH as u32, DISPLAY_HEIGHT as u32)
+            .map_err(|e| e.to_string())?;
+
+        Ok(Screen {
+            texture,
+            pixels: vec![0; DISPLAY_WIDTH * DISPLAY_HEIGHT * BYTES_PER_PIXEL],
+            palette,
+        })
+    }
+
+    /** Converts the most recent frame to RGB, blending fading pixels between
+     *  the background and foreground, and uploads it to the texture */
+    pub fn update(&mut self, frames: &Persistence) -> Result<(), String> {
+        let (off, on) = (self.palette.color(0), self.palette.color(1));
+        for (index, pixel) in self.pixels.chunks_mut(BYTES_PER_PIXEL).enumerate() {
+            let level = frames.level(index);
+            pixel[0] = blend(off.r, on.r, level);
+            pixel[1] = blend(off.g, on.g, level);
+            pixel[2] = blend(off.b, on.b, level);
+        }
+        self.texture
+            .update(None, &self.pixels, DISPLAY_WIDTH * BYTES_PER_PIXEL)
+            .map_err(|e| e.to_string())
+    }
+
+    /** Clears the window and copies the texture, letterboxed, into `area` */
+    pub fn draw(&self, canvas: &mut Canvas<Window>, area: Rect) -> Result<(), String> {
+        canvas.set_draw_color(Color::RGB(0, 0, 0));
+        canvas.clear();
+        let mut target = letterbox(area.width(), area.height());
+        target.offset(area.x(), area.y());
+        canvas.copy(&self.texture, None, Some(target))
+    }
+}
+
+fn blend(from: u8, to: u8, level: f32) -> u8 {
+    (from as f32 + (to as f32 - from as f32) * level).round() as u8
+}
+
+/** The largest area with the display's 2:1 aspect ratio that fits in a
+ *  `width` x `height` window, centered. Integer scales are preferred so
+ *  pixels stay square and even. */
+pub fn letterbox(width: u32, height: u32) -> Rect {
+    let (dw, dh) = (DISPLAY_WIDTH as u32, DISPLAY_HEIGHT as u32);
+    let scale = (width / dw).min(height / dh);
+    let (w, h) = if scale > 0 {
+        (dw * scale, dh * scale)
+    } else if width * dh < height * dw {
+        (width.max(1), (width * dh / dw).max(1))
+    } else {
+        ((height * dw / dh).max(1), height.max(1))
+    };
+    Rect::new((width.saturating_sub(w) / 2) as i32, (height.saturating_sub(h) / 2) as i32, w, h)
+}
+
+/** Switches between windowed and borderless fullscreen */
+pub fn toggle_fullscreen(canvas: &mut Canvas<Window>) -> Result<(), String> {
+    let window = canvas.window_mut();
+    let next = match window.fullscreen_state() {
+        FullscreenType::Off => FullscreenType::Desktop,
+        _ => FullscreenType::Off,
+    };
+    window.set_fullscreen(next)
+}
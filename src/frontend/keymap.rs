@@ -0,0 +1,89 @@
+/*  keymap.rs
+ *  Maps host keyboard keys to the 16-key CHIP-8 hex keypad */
+
+use std::collections::HashMap;
+
+use sdl2::keyboard::Scancode;
+
+/// Default layout: the left-hand 4x4 block of a QWERTY keyboard stands in for
+/// the VIP keypad, row by row.
+///
+/// ```text
+///   1 2 3 4        1 2 3 C
+///   Q W E R   ->   4 5 6 D
+///   A S D F        7 8 9 E
+///   Z X C V        A 0 B F
+/// ```
+const DEFAULT_LAYOUT: [(u8, Scancode); 16] = [
+    (0x1, Scancode::Num1), (0x2, Scancode::Num2), (0x3, Scancode::Num3), (0xC, Scancode::Num4),
+    (0x4, Scancode::Q), (0x5, Scancode::W), (0x6, Scancode::E), (0xD, Scancode::R),
+    (0x7, Scancode::A), (0x8, Scancode::S), (0x9, Scancode::D), (0xE, Scancode::F),
+    (0xA, Scancode::Z), (0x0, Scancode::X), (0xB, Scancode::C), (0xF, Scancode::V),
+];
+
+/// Keys the emulator handles itself (see the key list in `--help`). Their
+/// handlers run before the keypad lookup, so they cannot be rebound.
+const HOTKEYS: [Scancode; 19] = [
+    Scancode::P, Scancode::N, Scancode::Period, Scancode::M, Scancode::Backspace,
+    Scancode::LeftBracket, Scancode::RightBracket, Scancode::Backslash, Scancode::Minus, Scancode::Equals,
+    Scancode::F1, Scancode::F2, Scancode::F3, Scancode::F4, Scancode::F5,
+    Scancode::F6, Scancode::F7, Scancode::F9, Scancode::F11,
+];
+
+/// Host key bindings, by physical key position (scancode) so the layout stays
+/// put on non-QWERTY keyboards.
+#[derive(Clone)]
+pub struct KeyMap {
+    bindings: HashMap<Scancode, u8>,
+}
+
+impl Default for KeyMap {
+    fn default() -> KeyMap {
+        KeyMap {
+            bindings: DEFAULT_LAYOUT.iter().map(|&(key, sc)| (sc, key)).collect(),
+        }
+    }
+}
+
+impl KeyMap {
+    /** The CHIP-8 key bound to `scancode`, if any */
+    pub fn lookup(&self, scancode: Scancode) -> Option<u8> {
+        self.bindings.get(&scancode).cloned()
+    }
+
+    /** All bindings, as `(scancode, key)` pairs */
+    pub fn iter<'a>(&'a self) -> impl Iterator<Item = (Scancode, u8)> + 'a {
+        self.bindings.iter().map(|(&sc, &key)| (sc, key))
+    }
+
+    /** Binds CHIP-8 key `key` (0x0-0xF) to `scancode`, replacing its previous binding */
+    pub fn bind(&mut self, key: u8, scancode: Scancode) {
+        self.bindings.retain(|_, k| *k != key);
+        self.bindings.insert(scancode, key);
+    }
+
+    /** Applies overrides written as `KEY=NAME` pairs separated by commas, where
+     *  KEY is a hex digit and NAME an SDL key name, e.g. `5=Up,8=Down,0=Space` */
+    pub fn apply_overrides(&mut self, spec: &str) -> Result<(), String> {
+        for binding in spec.split(',').map(str::trim).filter(|b| !b.is_empty()) {
+            let mut parts = binding.splitn(2, '=');
+            let (key, name) = match (parts.next(), parts.next()) {
+                (Some(key), Some(name)) => (key.trim(), name.trim()),
+                _ => return Err(format!("key binding `{}` is not of the form KEY=NAME", binding)),
+            };
+            let key = match u8::from_str_radix(key, 16) {
+                Ok(key) if key <= 0xF => key,
+                _ => return Err(format!("`{}` is not a keypad key (0-F)", key)),
+            };
+            let scancode = match Scancode::from_name(name) {
+                Some(scancode) => scancode,
+                None => return Err(format!("unknown key name `{}`", name)),
+            };
+            if HOTKEYS.contains(&scancode) {
+                return Err(format!("`{}` is an emulator hotkey and cannot be bound to the keypad", name));
+            }
+            self.bind(key, scancode);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,16 @@
+/*  frontend/mod.rs
+ *  Pieces of the SDL frontend that sit between SDL and the chip8remu core */
+
+pub mod audio;
+pub mod config;
+pub mod dap;
+pub mod debugger;
+pub mod display;
+pub mod keymap;
+pub mod options;
+pub mod overlay;
+pub mod palette;
+pub mod persistence;
+pub mod states;
+pub mod rewind;
+pub mod speed;
@@ -0,0 +1,280 @@
+/*  options.rs
+ *  Command-line options for the emulator binary */
+
+use chip8remu::{Quirks, TraceFormat};
+use chip8remu::chip8::DEFAULT_IPF;
+use chip8remu::number;
+use chip8remu::quirks::PRESET_NAMES;
+use chip8remu::trace::OpcodePattern;
+
+use frontend::audio;
+use frontend::config::Settings;
+use frontend::display;
+use frontend::keymap::KeyMap;
+use frontend::palette::Palette;
+use frontend::rewind;
+
+pub const USAGE: &str = "usage: chip8remu [options] <rom.ch8>\n       chip8remu --dap [options]";
+
+pub const HELP: &str = "\
+Options:
+    --scale N              window size in host pixels per CHIP-8 pixel (default 10)
+    --ipf N                instructions executed per 60 Hz frame (default 10)
+    --quirks PROFILE       vip, chip48, schip or xochip (default vip)
+    --palette NAME|COLORS  classic, green, amber, lcd, octo, or 2-4 RRGGBB
+                           colors separated by commas, background first
+    --persistence FRAMES   fade unlit pixels out over FRAMES frames (default 0)
+    --rewind SECONDS       length of the rewind history; 0 disables it (default 60,
+                           at most 300)
+    --seed N               seed the random number generator for reproducible runs
+    --start-paused         start paused; N single-steps, P resumes (alias --step)
+    --debug                start paused with a debugger console on stdin
+    --dap                  serve the Debug Adapter Protocol on stdin/stdout instead
+                           of opening a window; the client's launch request names
+                           the ROM (`program`) and may set `stopOnEntry`,
+                           `quirks`, `ipf` and `seed`, which override --config,
+                           --quirks, --ipf and --seed
+    --trace FILE           log every executed instruction to FILE (- for stdout)
+    --trace-format FORMAT  text or json (JSON Lines) (default text)
+    --trace-range RANGES   only trace instructions at these addresses, e.g.
+                           200-2FF,3A0 (hex)
+    --trace-opcodes PATS   only trace these opcodes, e.g. DXYN,F?33; hex digits
+                           must match, other characters match anything
+    --mute                 start with sound muted
+    --config FILE          read settings from FILE instead of the default
+                           config.toml (see below)
+    --pitch HZ             beep frequency (default 440)
+    --volume 0-1           beep volume (default 0.25)
+    --keys KEY=NAME,...    rebind keypad keys to SDL key names, e.g. 5=Up,0=Space
+                           (the hotkeys listed below cannot be bound)
+    -h, --help             print this help
+    -V, --version          print the version
+
+Settings not given as flags are taken from the ROM's section of the config
+file, then from its [defaults] section. ROM sections are keyed by the SHA-1
+of the ROM, so they apply whatever the file is called.
+
+Keys:
+    P pause, N single-step and . frame-advance while paused, M mute, F11 fullscreen,
+    F1 debug panel (registers, stack, disassembly and keypad),
+    [ and ] slower/faster (0.25x to 4x, then uncapped), \\ normal speed,
+    hold Backspace to rewind,
+    F3 reset, F4 reload the ROM from disk (or drop a ROM file on the window),
+    F5 save state, F9 load state, F6/F7 previous/next save slot (0-9),
+    - and = slower/faster (instructions per frame), F2 save settings for this ROM";
+
+/// Options that take a value.
+const VALUE_OPTIONS: [&str; 15] = [
+    "--scale", "--ipf", "--quirks", "--palette", "--persistence", "--rewind",
+    "--seed", "--trace", "--trace-format", "--trace-range", "--trace-opcodes",
+    "--pitch", "--volume", "--keys", "--config",
+];
+
+/// The command line as given, before config file settings are layered under it.
+pub struct Args {
+    /// Empty under `--dap`, where the client names the ROM
+    pub rom_path: String,
+    pub config_path: Option<String>,
+    pub seed: Option<u64>,
+    pub start_paused: bool,
+    pub debug: bool,
+    pub trace: Option<String>,
+    pub trace_format: TraceFormat,
+    /// Inclusive address ranges to trace; empty traces everywhere
+    pub trace_ranges: Vec<(u16, u16)>,
+    /// Opcodes to trace; empty traces all of them
+    pub trace_opcodes: Vec<OpcodePattern>,
+    /// Settings given as flags; these override the config file
+    pub settings: Settings,
+}
+
+/// What the command line asks for.
+pub enum Command {
+    Run(Box<Args>),
+    /// Serve a debug session over stdio; the ROM comes from the client
+    Dap(Box<Args>),
+    Help,
+    Version,
+}
+
+/// Everything needed to start the emulator, with all layers resolved.
+pub struct Options {
+    pub scale: u32,
+    pub ipf: u32,
+    pub quirks: Quirks,
+    pub palette: Palette,
+    pub persistence: u8,
+    pub rewind: u32,
+    pub mute: bool,
+    pub pitch: f32,
+    pub volume: f32,
+    pub keymap: KeyMap,
+}
+
+impl Options {
+    /** Fills in `settings` over the built-in defaults, checking that every
+     *  value is valid */
+    pub fn resolve(settings: &Settings) -> Result<Options, String> {
+        let mut options = Options {
+            scale: settings.scale.unwrap_or(display::DEFAULT_SCALE).max(1),
+            ipf: settings.ipf.unwrap_or(DEFAULT_IPF).max(1),
+            quirks: Quirks::default(),
+            palette: Palette::default(),
+            persistence: settings.persistence.unwrap_or(0),
+            rewind: settings.rewind.unwrap_or(rewind::DEFAULT_SECONDS),
+            mute: settings.mute.unwrap_or(false),
+            pitch: settings.pitch.unwrap_or(audio::DEFAULT_PITCH),
+            volume: settings.volume.unwrap_or(audio::DEFAULT_VOLUME),
+            keymap: KeyMap::default(),
+        };
+        if options.rewind > rewind::MAX_SECONDS {
+            return Err(format!("rewind: {} seconds is longer than the {} second maximum",
+                               options.rewind, rewind::MAX_SECONDS));
+        }
+        if let Some(ref name) = settings.quirks {
+            options.quirks = parse_quirks(name)?;
+        }
+        if let Some(ref spec) = settings.palette {
+            options.palette = Palette::parse(spec).map_err(|e| format!("palette: {}", e))?;
+        }
+        if let Some(ref spec) = settings.keys {
+            options.keymap.apply_overrides(spec).map_err(|e| format!("keys: {}", e))?;
+        }
+        Ok(options)
+    }
+}
+
+fn parse_quirks(name: &str) -> Result<Quirks, String> {
+    Quirks::from_name(name).ok_or_else(|| {
+        format!("unknown quirks profile `{}` (expected one of {})", name, PRESET_NAMES.join(", "))
+    })
+}
+
+/** Parses the value of a numeric option */
+fn number<T: ::std::str::FromStr>(name: &str, value: &str) -> Result<T, String> {
+    value.parse().map_err(|_| format!("{} expects a number, got `{}`", name, value))
+}
+
+/** Parses an address range such as `200-2FF`, or a single address, in hex */
+fn parse_range(text: &str) -> Result<(u16, u16), String> {
+    let address = |text: &str| match number::parse(text, 16) {
+        Some(addr) if (0..=0xFFFF).contains(&addr) => Ok(addr as u16),
+        _ => Err(format!("bad address `{}` in --trace-range", text.trim())),
+    };
+    let (start, end) = match text.find('-') {
+        Some(dash) => (address(&text[..dash])?, address(&text[dash + 1..])?),
+        None => {
+            let addr = address(text)?;
+            (addr, addr)
+        },
+    };
+    if start > end {
+        return Err(format!("--trace-range {} ends before it starts", text));
+    }
+    Ok((start, end))
+}
+
+/** Parses the arguments following the program name. Options taking a value
+ *  accept it either as the next argument or after `=`. */
+pub fn parse<I: Iterator<Item = String>>(mut args: I) -> Result<Command, String> {
+    let mut rom_path = None;
+    let mut dap = false;
+    let mut parsed = Args {
+        rom_path: String::new(),
+        config_path: None,
+        seed: None,
+        start_paused: false,
+        debug: false,
+        trace: None,
+        trace_format: TraceFormat::Text,
+        trace_ranges: Vec::new(),
+        trace_opcodes: Vec::new(),
+        settings: Settings::default(),
+    };
+    let settings = &mut parsed.settings;
+
+    while let Some(arg) = args.next() {
+        if !arg.starts_with('-') || arg == "-" {
+            if rom_path.is_some() {
+                return Err(format!("unexpected argument `{}`", arg));
+            }
+            rom_path = Some(arg);
+            continue;
+        }
+
+        let (name, inline) = match arg.find('=') {
+            Some(eq) => (arg[..eq].to_string(), Some(arg[eq + 1..].to_string())),
+            None => (arg.clone(), None),
+        };
+
+        match name.as_str() {
+            "-h" | "--help" => return Ok(Command::Help),
+            "-V" | "--version" => return Ok(Command::Version),
+            "--dap" => dap = true,
+            "--start-paused" | "--step" => parsed.start_paused = true,
+            "--debug" => parsed.debug = true,
+            "--mute" => settings.mute = Some(true),
+            _ if !VALUE_OPTIONS.contains(&name.as_str()) => return Err(format!("unknown option `{}`", name)),
+            _ => {
+                let value = match inline.or_else(|| args.next()) {
+                    Some(value) => value,
+                    None => return Err(format!("{} expects a value", name)),
+                };
+                // Validate eagerly so mistakes are reported against the flag
+                match name.as_str() {
+                    "--scale" => match number(&name, &value)? {
+                        0 => return Err("--scale must be at least 1".to_string()),
+                        scale => settings.scale = Some(scale),
+                    },
+                    "--ipf" => match number(&name, &value)? {
+                        0 => return Err("--ipf must be at least 1".to_string()),
+                        ipf => settings.ipf = Some(ipf),
+                    },
+                    "--quirks" => {
+                        parse_quirks(&value)?;
+                        settings.quirks = Some(value);
+                    },
+                    "--palette" => {
+                        Palette::parse(&value).map_err(|e| format!("--palette: {}", e))?;
+                        settings.palette = Some(value);
+                    },
+                    "--keys" => {
+                        KeyMap::default().apply_overrides(&value).map_err(|e| format!("--keys: {}", e))?;
+                        settings.keys = Some(value);
+                    },
+                    "--persistence" => settings.persistence = Some(number(&name, &value)?),
+                    "--rewind" => settings.rewind = Some(number(&name, &value)?),
+                    "--pitch" => settings.pitch = Some(number(&name, &value)?),
+                    "--volume" => settings.volume = Some(number(&name, &value)?),
+                    "--seed" => parsed.seed = Some(number(&name, &value)?),
+                    "--trace" => parsed.trace = Some(value),
+                    "--trace-format" => match TraceFormat::from_name(&value) {
+                        Some(format) => parsed.trace_format = format,
+                        None => return Err(format!("unknown trace format `{}` (expected text or json)", value)),
+                    },
+                    "--trace-range" => for range in value.split(',') {
+                        parsed.trace_ranges.push(parse_range(range)?);
+                    },
+                    "--trace-opcodes" => for pattern in value.split(',') {
+                        match OpcodePattern::parse(pattern.trim()) {
+                            Some(pattern) => parsed.trace_opcodes.push(pattern),
+                            None => return Err(format!("bad opcode pattern `{}` (expected four characters, e.g. DXYN)", pattern)),
+                        }
+                    },
+                    "--config" => parsed.config_path = Some(value),
+                    _ => unreachable!(),
+                }
+            },
+        }
+    }
+
+    match rom_path {
+        Some(path) if dap => Err(format!("unexpected argument `{}` (with --dap the client names the ROM)", path)),
+        None if dap => Ok(Command::Dap(Box::new(parsed))),
+        Some(path) => {
+            parsed.rom_path = path;
+            Ok(Command::Run(Box::new(parsed)))
+        },
+        None => Err("no ROM given".to_string()),
+    }
+}
@@ -0,0 +1,229 @@
+/*  overlay.rs
+ *  Debug panel drawn beside the game (F1): registers, the call stack, a
+ *  disassembly window around PC and the keypad, refreshed every frame. Text
+ *  is rendered with a built-in 5x7 font into a streaming texture, the same
+ *  way `Screen` draws the framebuffer, so it needs no font files. */
+
+use sdl2::pixels::PixelFormatEnum;
+use sdl2::rect::Rect;
+use sdl2::render::{Canvas, Texture, TextureCreator};
+use sdl2::video::{Window, WindowContext};
+
+use chip8remu::{Chip8, Instruction};
+
+/// Panel size in characters.
+const COLS: usize = 26;
+const ROWS: usize = 30;
+/// Character cell size in panel pixels: a 5x7 glyph plus spacing.
+const CELL_WIDTH: usize = 6;
+const CELL_HEIGHT: usize = 9;
+/// Border around the text, in panel pixels.
+const MARGIN: usize = 4;
+
+const PANEL_WIDTH: usize = COLS * CELL_WIDTH + 2 * MARGIN;
+const PANEL_HEIGHT: usize = ROWS * CELL_HEIGHT + 2 * MARGIN;
+
+const BYTES_PER_PIXEL: usize = 3;
+
+const BACKGROUND: [u8; 3] = [0x18, 0x18, 0x20];
+const TEXT: [u8; 3] = [0xC8, 0xC8, 0xC8];
+const LABEL: [u8; 3] = [0x70, 0x90, 0xC0];
+const HIGHLIGHT: [u8; 3] = [0xFF, 0xD0, 0x40];
+
+/// Instructions shown either side of PC.
+const DISASSEMBLY_CONTEXT: u16 = 5;
+
+/// Keypad keys in their physical COSMAC VIP layout.
+const KEYPAD: [[u8; 4]; 4] = [
+    [0x1, 0x2, 0x3, 0xC],
+    [0x4, 0x5, 0x6, 0xD],
+    [0x7, 0x8, 0x9, 0xE],
+    [0xA, 0x0, 0xB, 0xF],
+];
+
+pub struct Overlay<'r> {
+    texture: Texture<'r>,
+    /// RGB24 staging buffer, uploaded to `texture` in one call
+    pixels: Vec<u8>,
+    pub visible: bool,
+}
+
+impl<'r> Overlay<'r> {
+    pub fn new(creator: &'r TextureCreator<WindowContext>) -> Result<Overlay<'r>, String> {
+        let texture = creator
+            .create_texture_streaming(PixelFormatEnum::RGB24, PANEL_WIDTH as u32, PANEL_HEIGHT as u32)
+            .map_err(|e| e.to_string())?;
+
+        Ok(Overlay {
+            texture,
+            pixels: vec![0; PANEL_WIDTH * PANEL_HEIGHT * BYTES_PER_PIXEL],
+            visible: false,
+        })
+    }
+
+    /** Redraws the panel from the machine's current state and uploads it */
+    pub fn update(&mut self, core: &Chip8) -> Result<(), String> {
+        for pixel in self.pixels.chunks_mut(BYTES_PER_PIXEL) {
+            pixel.copy_from_slice(&BACKGROUND);
+        }
+
+        for (row, regs) in core.v.chunks(4).enumerate() {
+            let line: Vec<String> = regs.iter().enumerate()
+                .map(|(i, v)| format!("V{:X} {:02X}", row * 4 + i, v))
+                .collect();
+            self.text(0, row, &line.join("  "), TEXT);
+        }
+        self.text(0, 4, &format!("I {:03X}  DT {:02X}  ST {:02X}", core.i, core.dt, core.st), TEXT);
+        self.text(0, 5, &format!("PC {:03X}  SP {:X}", core.pc, core.sp), TEXT);
+
+        // Disassembly around PC, which sits in the middle row
+        let top = 7;
+        for line in 0..2 * DISASSEMBLY_CONTEXT + 1 {
+            let addr = match core.pc.wrapping_add(2 * line).checked_sub(2 * DISASSEMBLY_CONTEXT) {
+                Some(addr) => addr,
+                None => continue,
+            };
+            let word = match core.fetch(addr) {
+                Some(word) => word,
+                None => continue,
+            };
+            let mnemonic = Instruction::decode(word).map_or("???".to_string(), |instr| instr.to_string());
+            let (marker, color) = if addr == core.pc {
+                ('>', HIGHLIGHT)
+            } else if core.breakpoints.contains(addr) {
+                ('*', TEXT)
+            } else {
+                (' ', TEXT)
+            };
+            self.text(0, top + line as usize, &format!("{}{:03X} {:04X} {}", marker, addr, word, mnemonic), color);
+        }
+
+        // Call stack, oldest entry first, four to a row
+        let top = top + 2 * DISASSEMBLY_CONTEXT as usize + 2;
+        self.text(0, top, "STACK", LABEL);
+        let depth = (core.sp as usize).min(core.stack.len());
+        if depth == 0 {
+            self.text(6, top, "EMPTY", TEXT);
+        }
+        for (level, ret) in core.stack[..depth].iter().enumerate() {
+            self.text(1 + (level % 4) * 5, top + 1 + level / 4, &format!("{:03X}", ret), TEXT);
+        }
+
+        // Keypad with held keys highlighted
+        let top = top + 6;
+        self.text(0, top, "KEYPAD", LABEL);
+        for (row, keys) in KEYPAD.iter().enumerate() {
+            for (col, &key) in keys.iter().enumerate() {
+                let color = if core.is_key_down(key) { HIGHLIGHT } else { TEXT };
+                self.text(1 + col * 2, top + 1 + row, &format!("{:X}", key), color);
+            }
+        }
+
+        self.texture
+            .update(None, &self.pixels, PANEL_WIDTH * BYTES_PER_PIXEL)
+            .map_err(|e| e.to_string())
+    }
+
+    /** Copies the panel into `area` */
+    pub fn draw(&self, canvas: &mut Canvas<Window>, area: Rect) -> Result<(), String> {
+        canvas.copy(&self.texture, None, Some(area))
+    }
+
+    /** Draws `text` starting at character cell (`col`, `row`), clipped to the panel */
+    fn text(&mut self, col: usize, row: usize, text: &str, color: [u8; 3]) {
+        if row >= ROWS {
+            return;
+        }
+        for (offset, c) in text.chars().enumerate().take(COLS.saturating_sub(col)) {
+            let x0 = MARGIN + (col + offset) * CELL_WIDTH;
+            let y0 = MARGIN + row * CELL_HEIGHT;
+            for (y, bits) in glyph(c).iter().enumerate() {
+                for x in 0..5 {
+                    if bits & (0x10 >> x) != 0 {
+                        let index = ((y0 + y) * PANEL_WIDTH + x0 + x) * BYTES_PER_PIXEL;
+                        self.pixels[index..index + BYTES_PER_PIXEL].copy_from_slice(&color);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/** Splits a `width` x `height` window into the game area and, if the panel is
+ *  shown, the panel's area on the right. The panel fills the window height,
+ *  at a whole-number scale when it fits, and takes at most half the width. */
+pub fn layout(width: u32, height: u32, visible: bool) -> (Rect, Option<Rect>) {
+    if !visible {
+        return (Rect::new(0, 0, width.max(1), height.max(1)), None);
+    }
+    let (panel_width, panel_height) = (PANEL_WIDTH as f32, PANEL_HEIGHT as f32);
+    let mut scale = height as f32 / panel_height;
+    if scale >= 1.0 {
+        scale = scale.floor();
+    }
+    scale = scale.min(width as f32 / 2.0 / panel_width);
+
+    let (w, h) = (((panel_width * scale) as u32).max(1), ((panel_height * scale) as u32).max(1));
+    let game_width = width.saturating_sub(w).max(1);
+    (Rect::new(0, 0, game_width, height.max(1)), Some(Rect::new(game_width as i32, 0, w, h)))
+}
+
+/** Rows of a 5x7 glyph, most significant of the low five bits leftmost.
+ *  Covers what the panel prints: digits, capitals, the `x` of `0x` and the
+ *  punctuation used by mnemonics. Lowercase letters are drawn as capitals. */
+fn glyph(c: char) -> [u8; 7] {
+    match c {
+        ' ' => [0b00000, 0b00000, 0b00000, 0b00000, 0b00000, 0b00000, 0b00000],
+        '0' => [0b01110, 0b10001, 0b10011, 0b10101, 0b11001, 0b10001, 0b01110],
+        '1' => [0b00100, 0b01100, 0b00100, 0b00100, 0b00100, 0b00100, 0b01110],
+        '2' => [0b01110, 0b10001, 0b00001, 0b00010, 0b00100, 0b01000, 0b11111],
+        '3' => [0b11111, 0b00010, 0b00100, 0b00010, 0b00001, 0b10001, 0b01110],
+        '4' => [0b00010, 0b00110, 0b01010, 0b10010, 0b11111, 0b00010, 0b00010],
+        '5' => [0b11111, 0b10000, 0b11110, 0b00001, 0b00001, 0b10001, 0b01110],
+        '6' => [0b00110, 0b01000, 0b10000, 0b11110, 0b10001, 0b10001, 0b01110],
+        '7' => [0b11111, 0b00001, 0b00010, 0b00100, 0b01000, 0b01000, 0b01000],
+        '8' => [0b01110, 0b10001, 0b10001, 0b01110, 0b10001, 0b10001, 0b01110],
+        '9' => [0b01110, 0b10001, 0b10001, 0b01111, 0b00001, 0b00010, 0b01100],
+        'A' => [0b01110, 0b10001, 0b10001, 0b11111, 0b10001, 0b10001, 0b10001],
+        'B' => [0b11110, 0b10001, 0b10001, 0b11110, 0b10001, 0b10001, 0b11110],
+        'C' => [0b01110, 0b10001, 0b10000, 0b10000, 0b10000, 0b10001, 0b01110],
+        'D' => [0b11100, 0b10010, 0b10001, 0b10001, 0b10001, 0b10010, 0b11100],
+        'E' => [0b11111, 0b10000, 0b10000, 0b11110, 0b10000, 0b10000, 0b11111],
+        'F' => [0b11111, 0b10000, 0b10000, 0b11110, 0b10000, 0b10000, 0b10000],
+        'G' => [0b01110, 0b10001, 0b10000, 0b10111, 0b10001, 0b10001, 0b01111],
+        'H' => [0b10001, 0b10001, 0b10001, 0b11111, 0b10001, 0b10001, 0b10001],
+        'I' => [0b01110, 0b00100, 0b00100, 0b00100, 0b00100, 0b00100, 0b01110],
+        'J' => [0b00111, 0b00010, 0b00010, 0b00010, 0b00010, 0b10010, 0b01100],
+        'K' => [0b10001, 0b10010, 0b10100, 0b11000, 0b10100, 0b10010, 0b10001],
+        'L' => [0b10000, 0b10000, 0b10000, 0b10000, 0b10000, 0b10000, 0b11111],
+        'M' => [0b10001, 0b11011, 0b10101, 0b10101, 0b10001, 0b10001, 0b10001],
+        'N' => [0b10001, 0b10001, 0b11001, 0b10101, 0b10011, 0b10001, 0b10001],
+        'O' => [0b01110, 0b10001, 0b10001, 0b10001, 0b10001, 0b10001, 0b01110],
+        'P' => [0b11110, 0b10001, 0b10001, 0b11110, 0b10000, 0b10000, 0b10000],
+        'Q' => [0b01110, 0b10001, 0b10001, 0b10001, 0b10101, 0b10010, 0b01101],
+        'R' => [0b11110, 0b10001, 0b10001, 0b11110, 0b10100, 0b10010, 0b10001],
+        'S' => [0b01111, 0b10000, 0b10000, 0b01110, 0b00001, 0b00001, 0b11110],
+        'T' => [0b11111, 0b00100, 0b00100, 0b00100, 0b00100, 0b00100, 0b00100],
+        'U' => [0b10001, 0b10001, 0b10001, 0b10001, 0b10001, 0b10001, 0b01110],
+        'V' => [0b10001, 0b10001, 0b10001, 0b10001, 0b10001, 0b01010, 0b00100],
+        'W' => [0b10001, 0b10001, 0b10001, 0b10101, 0b10101, 0b10101, 0b01010],
+        'X' => [0b10001, 0b10001, 0b01010, 0b00100, 0b01010, 0b10001, 0b10001],
+        'Y' => [0b10001, 0b10001, 0b10001, 0b01010, 0b00100, 0b00100, 0b00100],
+        'Z' => [0b11111, 0b00001, 0b00010, 0b00100, 0b01000, 0b10000, 0b11111],
+        'x' => [0b00000, 0b00000, 0b10001, 0b01010, 0b00100, 0b01010, 0b10001],
+        ',' => [0b00000, 0b00000, 0b00000, 0b00000, 0b01100, 0b00100, 0b01000],
+        '.' => [0b00000, 0b00000, 0b00000, 0b00000, 0b00000, 0b01100, 0b01100],
+        ':' => [0b00000, 0b01100, 0b01100, 0b00000, 0b01100, 0b01100, 0b00000],
+        '[' => [0b01110, 0b01000, 0b01000, 0b01000, 0b01000, 0b01000, 0b01110],
+        ']' => [0b01110, 0b00010, 0b00010, 0b00010, 0b00010, 0b00010, 0b01110],
+        '(' => [0b00010, 0b00100, 0b01000, 0b01000, 0b01000, 0b00100, 0b00010],
+        ')' => [0b01000, 0b00100, 0b00010, 0b00010, 0b00010, 0b00100, 0b01000],
+        '>' => [0b01000, 0b00100, 0b00010, 0b00001, 0b00010, 0b00100, 0b01000],
+        '*' => [0b00000, 0b00100, 0b10101, 0b01110, 0b10101, 0b00100, 0b00000],
+        '-' => [0b00000, 0b00000, 0b00000, 0b11111, 0b00000, 0b00000, 0b00000],
+        '=' => [0b00000, 0b00000, 0b11111, 0b00000, 0b11111, 0b00000, 0b00000],
+        '/' => [0b00000, 0b00001, 0b00010, 0b00100, 0b01000, 0b10000, 0b00000],
+        c if c.is_ascii_lowercase() => glyph(c.to_ascii_uppercase()),
+        _ => [0b01110, 0b10001, 0b00001, 0b00010, 0b00100, 0b00000, 0b00100],
+    }
+}
@@ -0,0 +1,111 @@
+/*  palette.rs
+ *  Display colors. Entry 0 is the background, 1 the foreground; entries 2 and
+ *  3 are for multi-plane (XO-CHIP) displays, where a pixel's color index is
+ *  the bitmask of the planes it is lit in. */
+
+use sdl2::pixels::Color;
+
+/// Number of entries in a palette: one per combination of two display planes.
+pub const PALETTE_SIZE: usize = 4;
+
+/// Names accepted by `Palette::from_name`.
+pub const PRESET_NAMES: [&str; 5] = ["classic", "green", "amber", "lcd", "octo"];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Palette {
+    pub colors: [Color; PALETTE_SIZE],
+}
+
+impl Palette {
+    /** Builds a palette from `(r, g, b)` triples, background first */
+    pub fn from_rgb(colors: [(u8, u8, u8); PALETTE_SIZE]) -> Palette {
+        let mut palette = Palette { colors: [Color::RGB(0, 0, 0); PALETTE_SIZE] };
+        for (slot, &(r, g, b)) in palette.colors.iter_mut().zip(colors.iter()) {
+            *slot = Color::RGB(r, g, b);
+        }
+        palette
+    }
+
+    /** White on black */
+    pub fn classic() -> Palette {
+        Palette::from_rgb([(0x00, 0x00, 0x00), (0xFF, 0xFF, 0xFF), (0xAA, 0xAA, 0xAA), (0x55, 0x55, 0x55)])
+    }
+
+    /** P1 green phosphor monitor */
+    pub fn green() -> Palette {
+        Palette::from_rgb([(0x0A, 0x14, 0x0A), (0x33, 0xFF, 0x66), (0x1F, 0x99, 0x3D), (0x14, 0x66, 0x29)])
+    }
+
+    /** P3 amber phosphor monitor */
+    pub fn amber() -> Palette {
+        Palette::from_rgb([(0x14, 0x0C, 0x00), (0xFF, 0xB0, 0x00), (0xB3, 0x7B, 0x00), (0x66, 0x46, 0x00)])
+    }
+
+    /** Gray-green LCD, as on the HP-48 */
+    pub fn lcd() -> Palette {
+        Palette::from_rgb([(0xA7, 0xB5, 0x9E), (0x2B, 0x33, 0x26), (0x5C, 0x66, 0x54), (0x80, 0x8C, 0x77)])
+    }
+
+    /** Octo's default colors */
+    pub fn octo() -> Palette {
+        Palette::from_rgb([(0x99, 0x66, 0x00), (0xFF, 0xCC, 0x00), (0xFF, 0x66, 0x00), (0x66, 0x22, 0x00)])
+    }
+
+    /** Looks up a preset by name (see `PRESET_NAMES`), ignoring case */
+    pub fn from_name(name: &str) -> Option<Palette> {
+        match name.to_lowercase().as_str() {
+            "classic" | "default" => Some(Palette::classic()),
+            "green" | "phosphor" => Some(Palette::green()),
+            "amber" => Some(Palette::amber()),
+            "lcd" | "gray" | "grey" => Some(Palette::lcd()),
+            "octo" => Some(Palette::octo()),
+            _ => None,
+        }
+    }
+
+    /** Parses either a preset name or a comma-separated list of two to four
+     *  `RRGGBB` colors (with an optional leading `#`), background first, e.g.
+     *  `#000000,#33FF66`. Missing plane colors repeat the foreground. */
+    pub fn parse(spec: &str) -> Result<Palette, String> {
+        if let Some(palette) = Palette::from_name(spec.trim()) {
+            return Ok(palette);
+        }
+
+        let neither = || format!("`{}` is neither a palette name ({}) nor 2-{} colors",
+                                 spec, PRESET_NAMES.join(", "), PALETTE_SIZE);
+        // Anything without a separator is a mistyped name, not a bad color
+        if !spec.contains(',') && !spec.contains('#') {
+            return Err(neither());
+        }
+        let colors = spec.split(',')
+            .map(|c| parse_color(c.trim()))
+            .collect::<Result<Vec<_>, _>>()?;
+        if colors.len() < 2 || colors.len() > PALETTE_SIZE {
+            return Err(neither());
+        }
+
+        let mut palette = Palette { colors: [colors[1]; PALETTE_SIZE] };
+        palette.colors[..colors.len()].copy_from_slice(&colors);
+        Ok(palette)
+    }
+
+    /** The color for a pixel lit in the planes given by the bitmask `planes` */
+    pub fn color(&self, planes: u8) -> Color {
+        self.colors[planes as usize % PALETTE_SIZE]
+    }
+}
+
+impl Default for Palette {
+    fn default() -> Palette {
+        Palette::classic()
+    }
+}
+
+/** Parses `RRGGBB` or `#RRGGBB` */
+fn parse_color(text: &str) -> Result<Color, String> {
+    let hex = text.trim_start_matches('#');
+    match u32::from_str_radix(hex, 16) {
+        Ok(rgb) if hex.len() == 6 => Ok(Color::RGB((rgb >> 16) as u8, (rgb >> 8) as u8, rgb as u8)),
+        _ => Err(format!("`{}` is not an RRGGBB color", text)),
+    }
+}
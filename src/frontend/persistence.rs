@@ -0,0 +1,60 @@
+/*  persistence.rs
+ *  Phosphor-persistence filter. CHIP-8 games move sprites by XORing them off
+ *  and back on, so a pixel is often dark for a frame or two while the sprite
+ *  is redrawn. Instead of switching off instantly, pixels fade out over a few
+ *  frames, which hides the flicker the way a real CRT's afterglow did. */
+
+use chip8remu::{DISPLAY_WIDTH, DISPLAY_HEIGHT};
+
+/// Frame history for the persistence filter, kept separate from the core's
+/// framebuffer so the emulated state is untouched.
+pub struct Persistence {
+    /// How many frames an unlit pixel takes to fade out; 0 disables the filter
+    frames: u8,
+    /// Frames since each pixel was last lit, saturating at `frames + 1`
+    age: Vec<u8>,
+}
+
+impl Persistence {
+    pub fn new(frames: u8) -> Persistence {
+        Persistence {
+            frames,
+            age: vec![frames.saturating_add(1); DISPLAY_WIDTH * DISPLAY_HEIGHT],
+        }
+    }
+
+    /** How many frames unlit pixels take to fade out */
+    pub fn frames(&self) -> u8 {
+        self.frames
+    }
+
+    /** Records one emulated frame */
+    pub fn push(&mut self, framebuffer: &[[bool; DISPLAY_WIDTH]; DISPLAY_HEIGHT]) {
+        let limit = self.frames.saturating_add(1);
+        let lit = framebuffer.iter().flat_map(|row| row.iter());
+        for (age, &on) in self.age.iter_mut().zip(lit) {
+            *age = if on { 0 } else { age.saturating_add(1).min(limit) };
+        }
+    }
+
+    /** Shows the display part way through a frame (after a single step or a
+     *  debugger stop): newly lit pixels light up and newly unlit ones start
+     *  fading, but nothing else ages. Does nothing right after `push`. */
+    pub fn show(&mut self, framebuffer: &[[bool; DISPLAY_WIDTH]; DISPLAY_HEIGHT]) {
+        let limit = self.frames.saturating_add(1);
+        let lit = framebuffer.iter().flat_map(|row| row.iter());
+        for (age, &on) in self.age.iter_mut().zip(lit) {
+            *age = if on { 0 } else { (*age).max(1).min(limit) };
+        }
+    }
+
+    /** Brightness of the pixel at row-major `index`, from 0.0 (off) to 1.0 (lit) */
+    pub fn level(&self, index: usize) -> f32 {
+        let age = self.age[index];
+        if age == 0 {
+            1.0
+        } else {
+            1.0 - age as f32 / (self.frames as f32 + 1.0)
+        }
+    }
+}
@@ -0,0 +1,192 @@
+/*  rewind.rs
+ *  Rewind history: one snapshot per emulated frame, kept as the newest
+ *  encoded save state plus a ring of backward deltas. Each delta is the XOR
+ *  of two consecutive states, run-length encoded, so a frame that only moves
+ *  a sprite costs a few dozen bytes. Stepping back XORs the newest delta out
+ *  of the current state; the oldest deltas are simply dropped once the
+ *  history is full. */
+
+use std::collections::VecDeque;
+
+use chip8remu::{SaveState, TIMER_HZ};
+use chip8remu::state::STATE_SIZE;
+
+/// Default length of the history.
+pub const DEFAULT_SECONDS: u32 = 60;
+/// Longest history `--rewind` accepts.
+pub const MAX_SECONDS: u32 = 300;
+/// The oldest frames are dropped once deltas take up this much memory, so a
+/// ROM that rewrites most of memory every frame cannot exhaust the host.
+const MAX_BYTES: usize = 32 * 1024 * 1024;
+
+/// Delta tags: the XOR stored verbatim, or as (zero run, literal run) pairs.
+const RAW: u8 = 0;
+const RUNS: u8 = 1;
+
+pub struct Rewind {
+    /// Maximum number of deltas, i.e. frames that can be stepped back
+    capacity: usize,
+    /// Encoded newest state
+    latest: Option<Vec<u8>>,
+    /// Backward deltas, oldest first
+    deltas: VecDeque<Vec<u8>>,
+    /// Bytes held by `deltas`
+    delta_bytes: usize,
+}
+
+impl Rewind {
+    /** A history holding up to `seconds` of frames; 0 disables rewinding */
+    pub fn new(seconds: u32) -> Rewind {
+        Rewind {
+            capacity: (seconds as usize).saturating_mul(TIMER_HZ as usize),
+            latest: None,
+            deltas: VecDeque::new(),
+            delta_bytes: 0,
+        }
+    }
+
+    /** Records the state after a frame */
+    pub fn push(&mut self, state: &SaveState) {
+        if self.capacity == 0 {
+            return;
+        }
+        let bytes = state.to_bytes();
+        if let Some(previous) = self.latest.take() {
+            let delta = encode_delta(&previous, &bytes);
+            self.delta_bytes += delta.len();
+            self.deltas.push_back(delta);
+            while self.deltas.len() > self.capacity || self.delta_bytes > MAX_BYTES {
+                if let Some(oldest) = self.deltas.pop_front() {
+                    self.delta_bytes -= oldest.len();
+                }
+            }
+        }
+        self.latest = Some(bytes);
+    }
+
+    /** Steps back one frame, returning the state to restore, or `None` once
+     *  the oldest recorded frame has been reached */
+    pub fn step_back(&mut self) -> Option<SaveState> {
+        let delta = self.deltas.pop_back()?;
+        self.delta_bytes -= delta.len();
+        let latest = self.latest.as_mut()?;
+        apply_delta(latest, &delta);
+        SaveState::from_bytes(latest).ok()
+    }
+
+    /** How far back the history currently reaches */
+    pub fn seconds(&self) -> f32 {
+        self.deltas.len() as f32 / TIMER_HZ as f32
+    }
+
+    /** Memory currently used by the history */
+    pub fn bytes(&self) -> usize {
+        self.delta_bytes + self.latest.as_ref().map_or(0, |l| l.len())
+    }
+
+    /** Upper bound on `bytes`: no delta is larger than a full state plus its
+     *  tag, and deltas never total more than `MAX_BYTES` */
+    pub fn budget(&self) -> usize {
+        self.capacity.saturating_mul(STATE_SIZE + 1).min(MAX_BYTES) + STATE_SIZE
+    }
+}
+
+/** Encodes `old ^ new`, picking whichever of the two representations is smaller */
+fn encode_delta(old: &[u8], new: &[u8]) -> Vec<u8> {
+    let xor: Vec<u8> = old.iter().zip(new.iter()).map(|(a, b)| a ^ b).collect();
+
+    let mut runs = vec![RUNS];
+    let mut pos = 0;
+    while pos < xor.len() && runs.len() <= xor.len() {
+        let zeros = xor[pos..].iter().take(0xFFFF).take_while(|&&b| b == 0).count();
+        pos += zeros;
+        let literal = xor[pos..].iter().take(0xFFFF).take_while(|&&b| b != 0).count();
+        runs.extend_from_slice(&(zeros as u16).to_be_bytes());
+        runs.extend_from_slice(&(literal as u16).to_be_bytes());
+        runs.extend_from_slice(&xor[pos..pos + literal]);
+        pos += literal;
+    }
+
+    if runs.len() <= xor.len() {
+        runs
+    } else {
+        let mut raw = Vec::with_capacity(xor.len() + 1);
+        raw.push(RAW);
+        raw.extend_from_slice(&xor);
+        raw
+    }
+}
+
+/** XORs a delta from `encode_delta` into `state` */
+fn apply_delta(state: &mut [u8], delta: &[u8]) {
+    match delta[0] {
+        RAW => {
+            for (byte, d) in state.iter_mut().zip(delta[1..].iter()) {
+                *byte ^= d;
+            }
+        },
+        _ => {
+            let (mut pos, mut i) = (0, 1);
+            while i + 4 <= delta.len() {
+                let zeros = u16::from_be_bytes([delta[i], delta[i + 1]]) as usize;
+                let literal = u16::from_be_bytes([delta[i + 2], delta[i + 3]]) as usize;
+                i += 4;
+                pos += zeros;
+                for (byte, d) in state[pos..pos + literal].iter_mut().zip(delta[i..i + literal].iter()) {
+                    *byte ^= d;
+                }
+                pos += literal;
+                i += literal;
+            }
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chip8remu::Chip8;
+
+    fn round_trip(old: &[u8], new: &[u8], tag: u8) {
+        let delta = encode_delta(old, new);
+        assert_eq!(delta[0], tag);
+        let mut state = new.to_vec();
+        apply_delta(&mut state, &delta);
+        assert_eq!(state, old);
+    }
+
+    #[test]
+    fn sparse_changes_use_runs() {
+        let old = vec![0x55; STATE_SIZE];
+        let mut new = old.clone();
+        new[0] = 0xAA;
+        new[1000..1008].copy_from_slice(&[1, 2, 3, 4, 5, 6, 7, 8]);
+        new[STATE_SIZE - 1] = 0;
+        round_trip(&old, &new, RUNS);
+        round_trip(&old, &old, RUNS);
+    }
+
+    #[test]
+    fn history_is_bounded_by_bytes() {
+        let mut rewind = Rewind::new(MAX_SECONDS);
+        let mut state = Chip8::new().save_state();
+        // Rewriting all of memory every frame makes every delta a full one
+        let frames = MAX_BYTES / STATE_SIZE + 100;
+        for frame in 0..frames {
+            for (i, byte) in state.memory.iter_mut().enumerate() {
+                *byte = (frame + i) as u8 | 1;
+            }
+            rewind.push(&state);
+        }
+        assert!(rewind.bytes() <= rewind.budget());
+        assert!(rewind.seconds() < frames as f32 / TIMER_HZ as f32);
+        assert!(rewind.step_back().is_some());
+    }
+
+    #[test]
+    fn dense_changes_stay_raw() {
+        let old: Vec<u8> = (0..STATE_SIZE).map(|i| i as u8).collect();
+        let new: Vec<u8> = old.iter().enumerate().map(|(i, b)| b ^ if i % 2 == 0 { 0xFF } else { 0 }).collect();
+        round_trip(&old, &new, RAW);
+    }
+}
@@ -0,0 +1,59 @@
+/*  speed.rs
+ *  Emulation speed. The machine always advances in whole 60 Hz frames
+ *  (`Chip8::run_frame`), so changing speed only changes how many emulated
+ *  frames run per host frame. A run is therefore the same sequence of frames
+ *  at any speed, however fast or slow the host is. */
+
+use std::time::Duration;
+
+/// Selectable speeds as multiples of real time; `None` is uncapped.
+const SPEEDS: [Option<f32>; 6] = [Some(0.25), Some(0.5), Some(1.0), Some(2.0), Some(4.0), None];
+const NORMAL: usize = 2;
+
+/// When uncapped, how long to keep running frames before drawing the next host frame.
+pub const UNCAPPED_SLICE: Duration = Duration::from_millis(14);
+
+pub struct Throttle {
+    /// Index into `SPEEDS`
+    index: usize,
+    /// Fractional frames owed at slow speeds
+    credit: f32,
+}
+
+impl Throttle {
+    pub fn new() -> Throttle {
+        Throttle { index: NORMAL, credit: 0.0 }
+    }
+
+    pub fn faster(&mut self) {
+        self.index = (self.index + 1).min(SPEEDS.len() - 1);
+    }
+
+    pub fn slower(&mut self) {
+        self.index = self.index.saturating_sub(1);
+    }
+
+    /** Back to real time */
+    pub fn normal(&mut self) {
+        self.index = NORMAL;
+        self.credit = 0.0;
+    }
+
+    /** The current speed, e.g. `2x` or `uncapped` */
+    pub fn label(&self) -> String {
+        match SPEEDS[self.index] {
+            Some(factor) => format!("{}x", factor),
+            None => "uncapped".to_string(),
+        }
+    }
+
+    /** How many emulated frames to run during this host frame, or `None` to
+     *  run as many as fit in `UNCAPPED_SLICE` */
+    pub fn frames_due(&mut self) -> Option<u32> {
+        let factor = SPEEDS[self.index]?;
+        self.credit += factor;
+        let due = self.credit.floor();
+        self.credit -= due;
+        Some(due as u32)
+    }
+}
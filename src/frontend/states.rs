@@ -0,0 +1,42 @@
+/*  states.rs
+ *  Save state slots on disk: ten per ROM, in a directory named after the
+ *  ROM's config key so states never get mixed up between games */
+
+use std::env;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+
+use chip8remu::SaveState;
+
+/// Number of save slots per ROM, numbered from 0.
+pub const SLOTS: u8 = 10;
+
+/** Where states are kept: `$XDG_DATA_HOME/chip8remu/states`, falling back to
+ *  `~/.local/share` or, on Windows, `%APPDATA%` */
+pub fn default_dir() -> Option<PathBuf> {
+    let base = env::var_os("XDG_DATA_HOME").map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".local").join("share")))
+        .or_else(|| env::var_os("APPDATA").map(PathBuf::from));
+    base.map(|dir| dir.join("chip8remu").join("states"))
+}
+
+fn slot_path(dir: &Path, rom_key: &str, slot: u8) -> PathBuf {
+    dir.join(rom_key).join(format!("slot{}.c8s", slot))
+}
+
+/** Writes `state` to `slot`, returning the file it went to */
+pub fn save(dir: &Path, rom_key: &str, slot: u8, state: &SaveState) -> Result<PathBuf, String> {
+    let path = slot_path(dir, rom_key, slot);
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).map_err(|e| format!("{}: {}", parent.display(), e))?;
+    }
+    fs::write(&path, state.to_bytes()).map_err(|e| format!("{}: {}", path.display(), e))?;
+    Ok(path)
+}
+
+/** Reads the state in `slot` */
+pub fn load(dir: &Path, rom_key: &str, slot: u8) -> Result<SaveState, String> {
+    let path = slot_path(dir, rom_key, slot);
+    let mut file = File::open(&path).map_err(|_| format!("slot {} is empty", slot))?;
+    SaveState::read_from(&mut file).map_err(|e| format!("{}: {}", path.display(), e))
+}
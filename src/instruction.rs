@@ -0,0 +1,265 @@
+/*  instruction.rs
+ *  Decoded CHIP-8 instructions. The interpreter, disassembler, debugger and
+ *  trace output all go through `Instruction::decode` so they agree on what a
+ *  word means. Mnemonics follow Cowgod's technical reference. */
+
+use std::fmt;
+
+/// One decoded CHIP-8 instruction. `x`/`y` are register indices (0x0-0xF),
+/// `nn` an immediate byte, `n` a nibble and bare `u16`s 12-bit addresses.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Instruction {
+    /// 0NNN: call machine code routine (ignored by interpreters)
+    Sys(u16),
+    /// 00E0: clear the display
+    Cls,
+    /// 00EE: return from subroutine
+    Ret,
+    /// 1NNN: jump to NNN
+    Jp(u16),
+    /// 2NNN: call subroutine at NNN
+    Call(u16),
+    /// 3XNN: skip next instruction if VX == NN
+    SeVxByte { x: u8, nn: u8 },
+    /// 4XNN: skip next instruction if VX != NN
+    SneVxByte { x: u8, nn: u8 },
+    /// 5XY0: skip next instruction if VX == VY
+    SeVxVy { x: u8, y: u8 },
+    /// 6XNN: VX = NN
+    LdVxByte { x: u8, nn: u8 },
+    /// 7XNN: VX += NN, VF untouched
+    AddVxByte { x: u8, nn: u8 },
+    /// 8XY0: VX = VY
+    LdVxVy { x: u8, y: u8 },
+    /// 8XY1: VX |= VY
+    Or { x: u8, y: u8 },
+    /// 8XY2: VX &= VY
+    And { x: u8, y: u8 },
+    /// 8XY3: VX ^= VY
+    Xor { x: u8, y: u8 },
+    /// 8XY4: VX += VY, VF = carry
+    AddVxVy { x: u8, y: u8 },
+    /// 8XY5: VX -= VY, VF = not borrow
+    Sub { x: u8, y: u8 },
+    /// 8XY6: VX >>= 1, VF = shifted out bit
+    Shr { x: u8, y: u8 },
+    /// 8XY7: VX = VY - VX, VF = not borrow
+    Subn { x: u8, y: u8 },
+    /// 8XYE: VX <<= 1, VF = shifted out bit
+    Shl { x: u8, y: u8 },
+    /// 9XY0: skip next instruction if VX != VY
+    SneVxVy { x: u8, y: u8 },
+    /// ANNN: I = NNN
+    LdI(u16),
+    /// BNNN: jump to NNN + V0
+    JpV0(u16),
+    /// CXNN: VX = random byte & NN
+    Rnd { x: u8, nn: u8 },
+    /// DXYN: draw an N-byte sprite from I at (VX, VY), VF = collision
+    Drw { x: u8, y: u8, n: u8 },
+    /// EX9E: skip next instruction if key VX is down
+    Skp { x: u8 },
+    /// EXA1: skip next instruction if key VX is up
+    Sknp { x: u8 },
+    /// FX07: VX = DT
+    LdVxDt { x: u8 },
+    /// FX0A: wait for a key press, store it in VX
+    LdVxK { x: u8 },
+    /// FX15: DT = VX
+    LdDtVx { x: u8 },
+    /// FX18: ST = VX
+    LdStVx { x: u8 },
+    /// FX1E: I += VX
+    AddIVx { x: u8 },
+    /// FX29: I = address of the font glyph for VX
+    LdFVx { x: u8 },
+    /// FX33: store BCD of VX at I, I+1, I+2
+    LdBVx { x: u8 },
+    /// FX55: store V0..=VX at I
+    LdIVx { x: u8 },
+    /// FX65: load V0..=VX from I
+    LdVxI { x: u8 },
+}
+
+impl Instruction {
+    /** Decodes a big-endian instruction word, or `None` if it is not a valid opcode */
+    pub fn decode(opcode: u16) -> Option<Instruction> {
+        use self::Instruction::*;
+
+        let nnn = opcode & 0x0FFF;
+        let nn = (opcode & 0x00FF) as u8;
+        let n = (opcode & 0x000F) as u8;
+        let x = ((opcode & 0x0F00) >> 8) as u8;
+        let y = ((opcode & 0x00F0) >> 4) as u8;
+
+        let instr = match opcode & 0xF000 {
+            0x0000 => match opcode {
+                0x00E0 => Cls,
+                0x00EE => Ret,
+                _ => Sys(nnn),
+            },
+            0x1000 => Jp(nnn),
+            0x2000 => Call(nnn),
+            0x3000 => SeVxByte { x, nn },
+            0x4000 => SneVxByte { x, nn },
+            0x5000 if n == 0 => SeVxVy { x, y },
+            0x6000 => LdVxByte { x, nn },
+            0x7000 => AddVxByte { x, nn },
+            0x8000 => match n {
+                0x0 => LdVxVy { x, y },
+                0x1 => Or { x, y },
+                0x2 => And { x, y },
+                0x3 => Xor { x, y },
+                0x4 => AddVxVy { x, y },
+                0x5 => Sub { x, y },
+                0x6 => Shr { x, y },
+                0x7 => Subn { x, y },
+                0xE => Shl { x, y },
+                _ => return None,
+            },
+            0x9000 if n == 0 => SneVxVy { x, y },
+            0xA000 => LdI(nnn),
+            0xB000 => JpV0(nnn),
+            0xC000 => Rnd { x, nn },
+            0xD000 => Drw { x, y, n },
+            0xE000 => match nn {
+                0x9E => Skp { x },
+                0xA1 => Sknp { x },
+                _ => return None,
+            },
+            0xF000 => match nn {
+                0x07 => LdVxDt { x },
+                0x0A => LdVxK { x },
+                0x15 => LdDtVx { x },
+                0x18 => LdStVx { x },
+                0x1E => AddIVx { x },
+                0x29 => LdFVx { x },
+                0x33 => LdBVx { x },
+                0x55 => LdIVx { x },
+                0x65 => LdVxI { x },
+                _ => return None,
+            },
+            _ => return None,
+        };
+        Some(instr)
+    }
+
+    /** Encodes back into an instruction word. Every instruction `decode`
+     *  returns encodes to the word it came from, but values `decode` never
+     *  produces do not survive the trip: fields wider than their nibble, byte
+     *  or 12 bits are masked, and `Sys(0x0E0)`/`Sys(0x0EE)` encode to `CLS`
+     *  and `RET`. */
+    pub fn encode(&self) -> u16 {
+        use self::Instruction::*;
+
+        fn xy(base: u16, x: u8, y: u8) -> u16 {
+            base | ((x as u16 & 0xF) << 8) | ((y as u16 & 0xF) << 4)
+        }
+        fn xnn(base: u16, x: u8, nn: u8) -> u16 {
+            base | ((x as u16 & 0xF) << 8) | nn as u16
+        }
+
+        match *self {
+            Sys(nnn) => nnn & 0x0FFF,
+            Cls => 0x00E0,
+            Ret => 0x00EE,
+            Jp(nnn) => 0x1000 | (nnn & 0x0FFF),
+            Call(nnn) => 0x2000 | (nnn & 0x0FFF),
+            SeVxByte { x, nn } => xnn(0x3000, x, nn),
+            SneVxByte { x, nn } => xnn(0x4000, x, nn),
+            SeVxVy { x, y } => xy(0x5000, x, y),
+            LdVxByte { x, nn } => xnn(0x6000, x, nn),
+            AddVxByte { x, nn } => xnn(0x7000, x, nn),
+            LdVxVy { x, y } => xy(0x8000, x, y),
+            Or { x, y } => xy(0x8001, x, y),
+            And { x, y } => xy(0x8002, x, y),
+            Xor { x, y } => xy(0x8003, x, y),
+            AddVxVy { x, y } => xy(0x8004, x, y),
+            Sub { x, y } => xy(0x8005, x, y),
+            Shr { x, y } => xy(0x8006, x, y),
+            Subn { x, y } => xy(0x8007, x, y),
+            Shl { x, y } => xy(0x800E, x, y),
+            SneVxVy { x, y } => xy(0x9000, x, y),
+            LdI(nnn) => 0xA000 | (nnn & 0x0FFF),
+            JpV0(nnn) => 0xB000 | (nnn & 0x0FFF),
+            Rnd { x, nn } => xnn(0xC000, x, nn),
+            Drw { x, y, n } => xy(0xD000, x, y) | (n as u16 & 0xF),
+            Skp { x } => xnn(0xE000, x, 0x9E),
+            Sknp { x } => xnn(0xE000, x, 0xA1),
+            LdVxDt { x } => xnn(0xF000, x, 0x07),
+            LdVxK { x } => xnn(0xF000, x, 0x0A),
+            LdDtVx { x } => xnn(0xF000, x, 0x15),
+            LdStVx { x } => xnn(0xF000, x, 0x18),
+            AddIVx { x } => xnn(0xF000, x, 0x1E),
+            LdFVx { x } => xnn(0xF000, x, 0x29),
+            LdBVx { x } => xnn(0xF000, x, 0x33),
+            LdIVx { x } => xnn(0xF000, x, 0x55),
+            LdVxI { x } => xnn(0xF000, x, 0x65),
+        }
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Instruction::*;
+
+        match *self {
+            Sys(nnn) => write!(f, "SYS 0x{:03X}", nnn),
+            Cls => write!(f, "CLS"),
+            Ret => write!(f, "RET"),
+            Jp(nnn) => write!(f, "JP 0x{:03X}", nnn),
+            Call(nnn) => write!(f, "CALL 0x{:03X}", nnn),
+            SeVxByte { x, nn } => write!(f, "SE V{:X}, 0x{:02X}", x, nn),
+            SneVxByte { x, nn } => write!(f, "SNE V{:X}, 0x{:02X}", x, nn),
+            SeVxVy { x, y } => write!(f, "SE V{:X}, V{:X}", x, y),
+            LdVxByte { x, nn } => write!(f, "LD V{:X}, 0x{:02X}", x, nn),
+            AddVxByte { x, nn } => write!(f, "ADD V{:X}, 0x{:02X}", x, nn),
+            LdVxVy { x, y } => write!(f, "LD V{:X}, V{:X}", x, y),
+            Or { x, y } => write!(f, "OR V{:X}, V{:X}", x, y),
+            And { x, y } => write!(f, "AND V{:X}, V{:X}", x, y),
+            Xor { x, y } => write!(f, "XOR V{:X}, V{:X}", x, y),
+            AddVxVy { x, y } => write!(f, "ADD V{:X}, V{:X}", x, y),
+            Sub { x, y } => write!(f, "SUB V{:X}, V{:X}", x, y),
+            Shr { x, y } => write!(f, "SHR V{:X}, V{:X}", x, y),
+            Subn { x, y } => write!(f, "SUBN V{:X}, V{:X}", x, y),
+            Shl { x, y } => write!(f, "SHL V{:X}, V{:X}", x, y),
+            SneVxVy { x, y } => write!(f, "SNE V{:X}, V{:X}", x, y),
+            LdI(nnn) => write!(f, "LD I, 0x{:03X}", nnn),
+            JpV0(nnn) => write!(f, "JP V0, 0x{:03X}", nnn),
+            Rnd { x, nn } => write!(f, "RND V{:X}, 0x{:02X}", x, nn),
+            Drw { x, y, n } => write!(f, "DRW V{:X}, V{:X}, {}", x, y, n),
+            Skp { x } => write!(f, "SKP V{:X}", x),
+            Sknp { x } => write!(f, "SKNP V{:X}", x),
+            LdVxDt { x } => write!(f, "LD V{:X}, DT", x),
+            LdVxK { x } => write!(f, "LD V{:X}, K", x),
+            LdDtVx { x } => write!(f, "LD DT, V{:X}", x),
+            LdStVx { x } => write!(f, "LD ST, V{:X}", x),
+            AddIVx { x } => write!(f, "ADD I, V{:X}", x),
+            LdFVx { x } => write!(f, "LD F, V{:X}", x),
+            LdBVx { x } => write!(f, "LD B, V{:X}", x),
+            LdIVx { x } => write!(f, "LD [I], V{:X}", x),
+            LdVxI { x } => write!(f, "LD V{:X}, [I]", x),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encode_inverts_decode() {
+        for word in 0..=0xFFFFu16 {
+            if let Some(instr) = Instruction::decode(word) {
+                assert_eq!(instr.encode(), word, "{}", instr);
+            }
+        }
+    }
+
+    #[test]
+    fn encode_masks_undecodable_values() {
+        assert_eq!(Instruction::Sys(0x0E0).encode(), 0x00E0);
+        assert_eq!(Instruction::Jp(0x1234).encode(), 0x1234);
+        assert_eq!(Instruction::Drw { x: 0x1F, y: 0, n: 5 }.encode(), 0xDF05);
+    }
+}
@@ -0,0 +1,29 @@
+/*  lib.rs
+ *  chip8remu library crate: a headless CHIP-8 interpreter core that frontends
+ *  (the SDL binary, test runners, bots, analyzers) drive through `Chip8` */
+
+extern crate rand;
+#[macro_use]
+extern crate serde_json;
+extern crate sha1;
+
+pub mod asm;
+pub mod chip8;
+pub mod dap;
+pub mod debug;
+pub mod disasm;
+pub mod error;
+pub mod instruction;
+pub mod number;
+pub mod pacer;
+pub mod quirks;
+pub mod state;
+pub mod trace;
+
+pub use chip8::{Chip8, DISPLAY_WIDTH, DISPLAY_HEIGHT, TIMER_HZ};
+pub use debug::{Access, Breakpoints, Register, StopReason, Watch};
+pub use error::{Chip8Error, FaultPolicy, RomError, StateError, StepOutcome};
+pub use instruction::Instruction;
+pub use quirks::Quirks;
+pub use state::SaveState;
+pub use trace::{TraceFormat, Tracer};
@@ -1,96 +1,471 @@
 /*  main.rs
- *  Initlizes SDL and chip8, provides core "game loop" */
+ *  SDL frontend: initializes SDL and a chip8remu core, provides the "game loop" */
 
 extern crate sdl2;
-extern crate rand;
+extern crate chip8remu;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate toml;
 
-use std::path::Path;
+use std::env;
+use std::fs::File;
+use std::io::prelude::*;
+use std::io::{self, BufWriter};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::time::Instant;
 
-use sdl2::rect::{Point, Rect};
-use sdl2::pixels::Color;
+use sdl2::EventPump;
 use sdl2::event::Event;
-use sdl2::mouse::MouseButton;
-use sdl2::keyboard::Keycode;
-use sdl2::video::{Window, WindowContext};
-use sdl2::render::{Canvas, Texture, TextureCreator};
+use sdl2::keyboard::Scancode;
 
-mod chip8;
+use chip8remu::{Chip8, Chip8Error, StepOutcome, Tracer, DISPLAY_WIDTH, DISPLAY_HEIGHT, TIMER_HZ};
+use chip8remu::pacer::FramePacer;
 
-fn main() {
-    // Instantiate SDL2
-    let ctx = sdl2::init().unwrap();
-    let video_ctx = ctx.video().unwrap();
+mod frontend;
 
-    // Create a window
-    let window = video_ctx
-        .window("", 64, 32)
-        .position_centered()
-        .build()
-        .unwrap();
+use frontend::audio::Beeper;
+use frontend::config::{self, Config, Settings};
+use frontend::dap;
+use frontend::debugger::{self, Debugger};
+use frontend::display::{self, Screen};
+use frontend::keymap::KeyMap;
+use frontend::options::{self, Args, Command, Options};
+use frontend::overlay::{self, Overlay};
+use frontend::persistence::Persistence;
+use frontend::states;
+use frontend::rewind::Rewind;
+use frontend::speed::{self, Throttle};
+
+const TITLE: &str = "chip8remu";
 
-    // Create a rendering context
-    let mut canvas = window.into_canvas().target_texture().present_vsync().build().unwrap();
+fn main() {
+    let args = match options::parse(env::args().skip(1)) {
+        Ok(Command::Run(args)) => *args,
+        Ok(Command::Help) => {
+            println!("{}\n\n{}", options::USAGE, options::HELP);
+            return;
+        },
+        Ok(Command::Dap(args)) => {
+            if let Err(e) = dap::serve(&args) {
+                eprintln!("chip8remu: {}", e);
+                process::exit(1);
+            }
+            return;
+        },
+        Ok(Command::Version) => {
+            println!("{} {}", TITLE, env!("CARGO_PKG_VERSION"));
+            return;
+        },
+        Err(e) => {
+            eprintln!("chip8remu: {}\n{}\nTry `chip8remu --help` for more information.", e, options::USAGE);
+            process::exit(2);
+        }
+    };
+
+    if let Err(e) = run(args) {
+        eprintln!("chip8remu: {}", e);
+        process::exit(1);
+    }
+}
 
-    // Set the drawing color to black
-    let _ = canvas.set_draw_color(sdl2::pixels::Color::RGB(0, 0, 0));
+/** Reads the ROM at `path` into `core` and works out its settings, layering
+ *  the command line over the config file. Returns the ROM's config key. */
+fn load_rom(core: &mut Chip8, path: &str, args: &Args, config_path: &Option<PathBuf>) -> Result<(String, Options), String> {
+    let mut rom = Vec::new();
+    File::open(path)
+        .and_then(|mut file| file.read_to_end(&mut rom))
+        .map_err(|e| format!("{}: {}", path, e))?;
+    let rom_key = config::rom_key(&rom);
 
-    // Clear the buffer, using black
-    let _ = canvas.clear();
+    let mut settings = match *config_path {
+        Some(ref config_path) => Config::load(config_path)?.settings_for(&rom_key),
+        None => Settings::default(),
+    };
+    settings.merge(&args.settings);
+    let options = Options::resolve(&settings)?;
 
+    core.load_rom_bytes(&rom).map_err(|e| format!("{}: {}", path, e))?;
+    core.quirks = options.quirks;
+    core.ipf = options.ipf;
+    Ok((rom_key, options))
+}
 
-    let _ = canvas.set_draw_color(sdl2::pixels::Color::RGB(255, 0, 255));
+/** Makes the keypad match the keys held on the host, after restoring a state
+ *  brought back whichever keys were held when it was saved */
+fn sync_keys(core: &mut Chip8, keymap: &KeyMap, events: &EventPump) {
+    for key in 0..16 {
+        core.set_key(key, false);
+    }
+    let keyboard = events.keyboard_state();
+    for (scancode, key) in keymap.iter() {
+        if keyboard.is_scancode_pressed(scancode) {
+            core.set_key(key, true);
+        }
+    }
+}
 
-    for y in 0..32 {
-        for x in 0..64 {
-            let _ = canvas.fill_rect(Rect::new(x, y, 1, 1));
+/** Runs `count` frames, or as many as fit in `speed::UNCAPPED_SLICE` if
+ *  `None`, recording each in the rewind history and the persistence filter.
+ *  Stops early at a fault, trap or debugger breakpoint and returns the
+ *  outcome of the last frame run, if any. */
+fn run_frames(core: &mut Chip8, rewind: &mut Rewind, persistence: &mut Persistence,
+              mut debugger: Option<&mut Debugger>, count: Option<u32>) -> Option<Result<StepOutcome, Chip8Error>> {
+    let start = Instant::now();
+    let mut result = None;
+    let mut frames = 0;
+    loop {
+        match count {
+            Some(count) if frames >= count => break,
+            None if frames > 0 && start.elapsed() >= speed::UNCAPPED_SLICE => break,
+            _ => {},
+        }
+        let outcome = match debugger {
+            Some(ref mut debugger) => match debugger.run_frame(core) {
+                Some(outcome) => outcome,
+                None => break,
+            },
+            None => core.run_frame(),
+        };
+        rewind.push(&core.save_state());
+        persistence.push(core.framebuffer());
+        frames += 1;
+        let done = !matches!(outcome, Ok(StepOutcome::Executed));
+        result = Some(outcome);
+        if done {
+            break;
         }
     }
+    result
+}
+
+/** Sets up the core and SDL, then runs the emulator until the window is closed */
+fn run(args: Args) -> Result<(), String> {
+    let config_path = args.config_path.as_ref().map(PathBuf::from).or_else(config::default_path);
 
-    // Swap out buffer for the present buffer, displaying it.
-    let _ = canvas.present();
+    //Initialize Chip8 system and load the ROM before opening any windows
+    let mut core = Chip8::new();
+    if let Some(seed) = args.seed {
+        core.seed_rng(seed);
+    }
+    let mut rom_path = args.rom_path.clone();
+    let (mut rom_key, options) = load_rom(&mut core, &rom_path, &args, &config_path)?;
+    if let Some(ref path) = args.trace {
+        let out: Box<dyn Write> = if path == "-" {
+            Box::new(io::stdout())
+        } else {
+            Box::new(BufWriter::new(File::create(path).map_err(|e| format!("{}: {}", path, e))?))
+        };
+        let mut tracer = Tracer::new(out, args.trace_format);
+        for &(start, end) in args.trace_ranges.iter() {
+            tracer.add_range(start, end);
+        }
+        for &pattern in args.trace_opcodes.iter() {
+            tracer.add_opcode(pattern);
+        }
+        core.set_trace(Some(tracer));
+    }
 
-    let mut events = ctx.event_pump().unwrap();
+    // Instantiate SDL2
+    let ctx = sdl2::init().map_err(|e| format!("could not initialize SDL: {}", e))?;
+    let video_ctx = ctx.video().map_err(|e| format!("could not initialize video: {}", e))?;
 
+    // Sound is optional: keep running silently if there is no audio device
+    let mut beeper = match ctx.audio().and_then(|audio_ctx| Beeper::new(&audio_ctx, options.pitch, options.volume)) {
+        Ok(mut beeper) => {
+            beeper.set_muted(options.mute);
+            Some(beeper)
+        },
+        Err(e) => {
+            eprintln!("Audio unavailable, running without sound: {}", e);
+            None
+        }
+    };
 
+    // Create a resizable window; the framebuffer is scaled to fit it
+    let (width, height) = (DISPLAY_WIDTH as u32 * options.scale, DISPLAY_HEIGHT as u32 * options.scale);
+    let window = video_ctx
+        .window(TITLE, width, height)
+        .position_centered()
+        .resizable()
+        .build()
+        .map_err(|e| format!("could not create window: {}", e))?;
 
-    //Initialize Chip8 system
-    let mut core =  chip8::Chip8{..Default::default()};
+    // Create a rendering context. Frames are paced by FramePacer, not vsync.
+    let mut canvas = window.into_canvas().build().map_err(|e| format!("could not create renderer: {}", e))?;
+    let _ = canvas.window_mut().set_minimum_size(DISPLAY_WIDTH as u32, DISPLAY_HEIGHT as u32);
+    let texture_creator = canvas.texture_creator();
+    let mut screen = Screen::new(&texture_creator, options.palette)?;
+    let mut overlay = Overlay::new(&texture_creator)?;
+    let mut persistence = Persistence::new(options.persistence);
+    let mut rewind = Rewind::new(options.rewind);
+    let mut rewinding = false;
+    let mut throttle = Throttle::new();
+    if options.rewind > 0 {
+        println!("Rewind: up to {} s of history, using at most {:.1} MiB",
+                 options.rewind, rewind.budget() as f32 / (1024.0 * 1024.0));
+    }
 
-    //Load ROM
-    core.load_rom();
+    let mut events = ctx.event_pump()?;
+    let mut keymap = options.keymap;
+    let mut pacer = FramePacer::new(TIMER_HZ);
+    let mut paused = args.start_paused;
+    let mut muted = options.mute;
+    let state_dir = states::default_dir();
+    let mut slot = 0;
 
+    let mut debugger = if args.debug { Some(Debugger::new()) } else { None };
+    if debugger.is_some() {
+        paused = true;
+    } else if paused {
+        println!("Single-step mode: N steps one instruction, P resumes");
+    }
+    let mut title_paused = false;
 
     // loop until we receive a QuitEvent
     'event : loop {
+        let mut single_step = false;
+        let mut frame_advance = false;
+        let mut reload = None;
+        let mut resync_keys = false;
+
         for event in events.poll_iter() {
             match event {
                 Event::Quit{..} => break 'event,
-                //"game loop" code
-                Event::KeyDown{..} => {
-                    core.emulate_cycle();
-
-                    //Update display
-                    for (y, row) in core.display.iter().enumerate() {
-                        for (x, pix) in row.iter().enumerate() {
-                            if *pix {
-                                let _ = canvas.set_draw_color(sdl2::pixels::Color::RGB(255, 255, 255));
-                            } else {
-                                let _ = canvas.set_draw_color(sdl2::pixels::Color::RGB(0, 0, 0));
-                            }
-                            let _ = canvas.fill_rect(Rect::new(x as i32, y as i32, 1, 1));
+                Event::KeyDown{scancode: Some(Scancode::P), repeat: false, ..} => {
+                    paused = !paused;
+                    if let Some(ref mut debugger) = debugger {
+                        if paused {
+                            debugger.stop(&core);
+                        } else {
+                            debugger.resume(&mut core);
                         }
                     }
+                },
+                Event::KeyDown{scancode: Some(Scancode::N), ..} if paused => {
+                    single_step = true;
+                },
+                Event::KeyDown{scancode: Some(Scancode::Period), ..} if paused => {
+                    frame_advance = true;
+                },
+                Event::KeyDown{scancode: Some(Scancode::LeftBracket), ..} => {
+                    throttle.slower();
+                    println!("Speed {}", throttle.label());
+                },
+                Event::KeyDown{scancode: Some(Scancode::RightBracket), ..} => {
+                    throttle.faster();
+                    println!("Speed {}", throttle.label());
+                },
+                Event::KeyDown{scancode: Some(Scancode::Backslash), repeat: false, ..} => {
+                    throttle.normal();
+                    println!("Speed {}", throttle.label());
+                },
+                Event::KeyDown{scancode: Some(Scancode::F1), repeat: false, ..} => {
+                    overlay.visible = !overlay.visible;
+                },
+                Event::KeyDown{scancode: Some(Scancode::F11), repeat: false, ..} => {
+                    if let Err(e) = display::toggle_fullscreen(&mut canvas) {
+                        eprintln!("Fullscreen: {}", e);
+                    }
+                },
+                Event::KeyDown{scancode: Some(Scancode::M), repeat: false, ..} => {
+                    if let Some(ref mut beeper) = beeper {
+                        muted = beeper.toggle_mute();
+                        println!("Sound {}", if muted { "muted" } else { "unmuted" });
+                    }
+                },
+                Event::KeyDown{scancode: Some(Scancode::F5), repeat: false, ..} => {
+                    let saved = match state_dir {
+                        Some(ref dir) => states::save(dir, &rom_key, slot, &core.save_state()),
+                        None => Err("no save state directory".to_string()),
+                    };
+                    match saved {
+                        Ok(path) => println!("Saved state to slot {} ({})", slot, path.display()),
+                        Err(e) => eprintln!("Could not save state: {}", e),
+                    }
+                },
+                Event::KeyDown{scancode: Some(Scancode::F9), repeat: false, ..} => {
+                    let loaded = match state_dir {
+                        Some(ref dir) => states::load(dir, &rom_key, slot)
+                            .and_then(|state| core.load_state(&state).map_err(|e| e.to_string())),
+                        None => Err("no save state directory".to_string()),
+                    };
+                    match loaded {
+                        Ok(()) => {
+                            resync_keys = true;
+                            persistence = Persistence::new(persistence.frames());
+                            println!("Loaded state from slot {}", slot);
+                        },
+                        Err(e) => eprintln!("Could not load state: {}", e),
+                    }
+                },
+                Event::KeyDown{scancode: Some(Scancode::F6), ..} => {
+                    slot = (slot + states::SLOTS - 1) % states::SLOTS;
+                    println!("Save slot {}", slot);
+                },
+                Event::KeyDown{scancode: Some(Scancode::F7), ..} => {
+                    slot = (slot + 1) % states::SLOTS;
+                    println!("Save slot {}", slot);
+                },
+                Event::KeyDown{scancode: Some(Scancode::F3), repeat: false, ..} => {
+                    core.reset();
+                    resync_keys = true;
+                    persistence = Persistence::new(persistence.frames());
+                    paused = args.start_paused;
+                    println!("Reset");
+                },
+                Event::KeyDown{scancode: Some(Scancode::F4), repeat: false, ..} => {
+                    reload = Some(rom_path.clone());
+                },
+                Event::DropFile{filename, ..} => {
+                    reload = Some(filename);
+                },
+                Event::KeyDown{scancode: Some(Scancode::Minus), ..} => {
+                    core.ipf = core.ipf.saturating_sub(1).max(1);
+                    println!("{} instructions per frame", core.ipf);
+                },
+                Event::KeyDown{scancode: Some(Scancode::Equals), ..} => {
+                    core.ipf += 1;
+                    println!("{} instructions per frame", core.ipf);
+                },
+                Event::KeyDown{scancode: Some(Scancode::F2), repeat: false, ..} => {
+                    // Remember this session's flags and in-app changes for the ROM
+                    let mut section = args.settings.clone();
+                    section.name = Path::new(&rom_path).file_name().map(|n| n.to_string_lossy().into_owned());
+                    section.ipf = Some(core.ipf);
+                    section.mute = Some(muted);
+                    match config_path {
+                        Some(ref path) => match Config::save_rom_settings(path, &rom_key, &section) {
+                            Ok(()) => println!("Saved settings for {} to {}", rom_key, path.display()),
+                            Err(e) => eprintln!("Could not save settings: {}", e),
+                        },
+                        None => eprintln!("Could not save settings: no config file location (use --config)"),
+                    }
+                },
+                Event::KeyDown{scancode: Some(sc), ..} => {
+                    if let Some(key) = keymap.lookup(sc) {
+                        core.set_key(key, true);
+                    }
+                },
+                Event::KeyUp{scancode: Some(sc), ..} => {
+                    if let Some(key) = keymap.lookup(sc) {
+                        core.set_key(key, false);
+                    }
+                },
+                _ => {}
+            }
+        }
 
-                    let _ = canvas.present();
+        // Load a new or changed ROM file, picking up its settings from the config
+        if let Some(path) = reload {
+            match load_rom(&mut core, &path, &args, &config_path) {
+                Ok((key, options)) => {
+                    println!("Loaded {}", path);
+                    rom_path = path;
+                    rom_key = key;
+                    screen.palette = options.palette;
+                    persistence = Persistence::new(options.persistence);
+                    rewind = Rewind::new(options.rewind);
+                    keymap = options.keymap;
+                    muted = options.mute;
+                    if let Some(ref mut beeper) = beeper {
+                        beeper.set_muted(muted);
+                    }
+                    paused = args.start_paused;
+                    resync_keys = true;
+                },
+                Err(e) => eprintln!("Could not load ROM: {}", e),
+            }
+        }
 
-                    core.dbg();
+        // Keys held through a reset or reload are still down
+        if resync_keys {
+            sync_keys(&mut core, &keymap, &events);
+        }
 
-                    continue;
-                },
+        if let Some(ref mut debugger) = debugger {
+            if debugger.poll(&mut core) == debugger::Action::Quit {
+                break 'event;
+            }
+            paused = debugger.paused;
+        }
+
+        // Holding the rewind key steps back one recorded frame per frame
+        let rewind_held = events.keyboard_state().is_scancode_pressed(Scancode::Backspace);
+        if rewind_held && !rewinding {
+            println!("Rewinding: {:.1} s of history in {} KiB", rewind.seconds(), rewind.bytes() / 1024);
+        }
+        rewinding = rewind_held;
+
+        //"game loop" code
+        let result = if rewinding {
+            if let Some(state) = rewind.step_back() {
+                if core.load_state(&state).is_ok() {
+                    sync_keys(&mut core, &keymap, &events);
+                }
+            }
+            persistence.push(core.framebuffer());
+            None
+        } else if single_step {
+            let result = core.step();
+            rewind.push(&core.save_state());
+            debugger::print_regs(&core);
+            Some(result)
+        } else if frame_advance {
+            run_frames(&mut core, &mut rewind, &mut persistence, debugger.as_mut(), Some(1))
+        } else if !paused {
+            run_frames(&mut core, &mut rewind, &mut persistence, debugger.as_mut(), throttle.frames_due())
+        } else {
+            None
+        };
+
+        // Single steps and debugger stops change the display between frames
+        persistence.show(core.framebuffer());
+
+        match result {
+            Some(Err(e)) => {
+                eprintln!("CPU halted: {}", e);
+                paused = true;
+            },
+            Some(Ok(StepOutcome::Trapped(e))) => {
+                eprintln!("Trapped: {}", e);
+                paused = true;
+            },
+            _ => {}
+        }
 
-                _ => {continue;}
+        if let Some(ref mut debugger) = debugger {
+            if paused {
+                debugger.stop(&core);
             }
+            paused = debugger.paused;
         }
+        if paused != title_paused {
+            title_paused = paused;
+            let title = if paused { format!("{} - paused", TITLE) } else { TITLE.to_string() };
+            let _ = canvas.window_mut().set_title(&title);
+        }
+
+        if let Some(ref mut beeper) = beeper {
+            beeper.set_active(!paused && !rewinding && core.sound_active());
+        }
+
+        //Update display, with the debug panel beside it if shown
+        let (width, height) = canvas.output_size()?;
+        let (game_area, panel_area) = overlay::layout(width, height, overlay.visible);
+        if let Err(e) = screen.update(&persistence).and_then(|_| screen.draw(&mut canvas, game_area)) {
+            eprintln!("Render error: {}", e);
+        }
+        if let Some(area) = panel_area {
+            if let Err(e) = overlay.update(&core).and_then(|_| overlay.draw(&mut canvas, area)) {
+                eprintln!("Render error: {}", e);
+            }
+        }
+        canvas.present();
+
+        pacer.wait();
     }
-}
 
+    Ok(())
+}
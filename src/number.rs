@@ -0,0 +1,47 @@
+/*  number.rs
+ *  Integer literals as typed by users: the assembler, the debugger console,
+ *  the DAP server and the command line all read numbers the same way. */
+
+/** Parses `0x` hex, `0b` binary or plain digits in `radix` (10 almost
+ *  everywhere, 16 where addresses are expected), optionally negative.
+ *  Surrounding whitespace is ignored. */
+pub fn parse(text: &str, radix: u32) -> Option<i64> {
+    let text = text.trim();
+    let (negative, text) = match text.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, text),
+    };
+    let (digits, radix) = if let Some(hex) = text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        (hex, 16)
+    } else if let Some(bin) = text.strip_prefix("0b").or_else(|| text.strip_prefix("0B")) {
+        (bin, 2)
+    } else {
+        (text, radix)
+    };
+    // from_str_radix would also take a sign here
+    if digits.is_empty() || !digits.chars().all(|c| c.is_digit(radix)) {
+        return None;
+    }
+    let value = i64::from_str_radix(digits, radix).ok()?;
+    Some(if negative { -value } else { value })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn literals() {
+        assert_eq!(parse("42", 10), Some(42));
+        assert_eq!(parse(" 0x2A ", 10), Some(42));
+        assert_eq!(parse("0X2a", 10), Some(42));
+        assert_eq!(parse("0b101010", 10), Some(42));
+        assert_eq!(parse("-0x10", 10), Some(-16));
+        assert_eq!(parse("2A", 16), Some(42));
+        assert_eq!(parse("2A", 10), None);
+        assert_eq!(parse("+5", 10), None);
+        assert_eq!(parse("0x", 10), None);
+        assert_eq!(parse("", 10), None);
+        assert_eq!(parse("0xFFFFFFFFFFFFFFFFFF", 10), None);
+    }
+}
@@ -0,0 +1,34 @@
+/*  pacer.rs
+ *  Sleeps the frame loop to a fixed rate instead of spinning on events */
+
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// If the loop falls this far behind (a debugger pause, a slow host), it
+/// resynchronizes instead of running a burst of catch-up frames.
+const MAX_LAG: Duration = Duration::from_millis(250);
+
+pub struct FramePacer {
+    period: Duration,
+    next: Instant,
+}
+
+impl FramePacer {
+    pub fn new(hz: u32) -> FramePacer {
+        FramePacer {
+            period: Duration::from_nanos(1_000_000_000 / hz as u64),
+            next: Instant::now(),
+        }
+    }
+
+    /** Blocks until the next frame is due */
+    pub fn wait(&mut self) {
+        self.next += self.period;
+        let now = Instant::now();
+        if self.next > now {
+            thread::sleep(self.next - now);
+        } else if now - self.next > MAX_LAG {
+            self.next = now;
+        }
+    }
+}
@@ -0,0 +1,105 @@
+/*  quirks.rs
+ *  Behavioral differences between CHIP-8 implementations. ROMs written for
+ *  one platform often misbehave on another, so each `Chip8` carries a
+ *  `Quirks` profile choosing how the ambiguous instructions behave. */
+
+/// How far `FX55`/`FX65` advance I after transferring V0..=VX.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IndexIncrement {
+    /// I += X + 1 (COSMAC VIP, XO-CHIP)
+    XPlusOne,
+    /// I += X (CHIP-48)
+    X,
+    /// I is left alone (SUPER-CHIP)
+    None,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Quirks {
+    /// `8XY6`/`8XYE` shift VX in place. When unset, VY is shifted into VX.
+    pub shift_vx: bool,
+    /// How `FX55`/`FX65` change I.
+    pub load_store: IndexIncrement,
+    /// `BNNN` jumps to XNN + VX (X being the top nibble of NNN) rather than NNN + V0.
+    pub jump_with_vx: bool,
+    /// `8XY1`/`8XY2`/`8XY3` reset VF to 0.
+    pub vf_reset: bool,
+    /// Sprites wrap around the screen edges instead of being clipped.
+    pub wrap_sprites: bool,
+    /// `DXYN` waits for the vertical blank, so at most one sprite is drawn per frame.
+    pub display_wait: bool,
+}
+
+/// Names accepted by `Quirks::from_name`.
+pub const PRESET_NAMES: [&str; 4] = ["vip", "chip48", "schip", "xochip"];
+
+impl Quirks {
+    /** The original COSMAC VIP interpreter */
+    pub fn cosmac_vip() -> Quirks {
+        Quirks {
+            shift_vx: false,
+            load_store: IndexIncrement::XPlusOne,
+            jump_with_vx: false,
+            vf_reset: true,
+            wrap_sprites: false,
+            display_wait: true,
+        }
+    }
+
+    /** CHIP-48 on the HP-48 calculators */
+    pub fn chip48() -> Quirks {
+        Quirks {
+            shift_vx: true,
+            load_store: IndexIncrement::X,
+            jump_with_vx: true,
+            vf_reset: false,
+            wrap_sprites: false,
+            display_wait: false,
+        }
+    }
+
+    /** SUPER-CHIP 1.1 */
+    pub fn superchip() -> Quirks {
+        Quirks {
+            shift_vx: true,
+            load_store: IndexIncrement::None,
+            jump_with_vx: true,
+            vf_reset: false,
+            wrap_sprites: false,
+            display_wait: false,
+        }
+    }
+
+    /** XO-CHIP, as implemented by Octo */
+    pub fn xochip() -> Quirks {
+        Quirks {
+            shift_vx: false,
+            load_store: IndexIncrement::XPlusOne,
+            jump_with_vx: false,
+            vf_reset: false,
+            wrap_sprites: true,
+            display_wait: false,
+        }
+    }
+
+    /** Looks up a preset by name (see `PRESET_NAMES`), ignoring case and `-`/`_` */
+    pub fn from_name(name: &str) -> Option<Quirks> {
+        let name: String = name.chars()
+            .filter(|c| *c != '-' && *c != '_')
+            .flat_map(|c| c.to_lowercase())
+            .collect();
+        match name.as_str() {
+            "vip" | "cosmacvip" | "chip8" => Some(Quirks::cosmac_vip()),
+            "chip48" => Some(Quirks::chip48()),
+            "schip" | "superchip" => Some(Quirks::superchip()),
+            "xochip" | "octo" => Some(Quirks::xochip()),
+            _ => None,
+        }
+    }
+}
+
+impl Default for Quirks {
+    fn default() -> Quirks {
+        Quirks::cosmac_vip()
+    }
+}
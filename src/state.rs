@@ -0,0 +1,319 @@
+/*  state.rs
+ *  Save states: a snapshot of everything that determines what a `Chip8` does
+ *  next, and a versioned binary encoding for storing them on disk.
+ *
+ *  Layout (all integers big-endian):
+ *
+ *  | size | field                                                     |
+ *  |------|-----------------------------------------------------------|
+ *  | 4    | magic `C8ST`                                              |
+ *  | 2    | format version (`STATE_VERSION`)                          |
+ *  | 20   | SHA-1 of the ROM the state was saved from                 |
+ *  | 4096 | memory                                                    |
+ *  | 16   | V0-VF                                                     |
+ *  | 2    | I                                                         |
+ *  | 1+1  | DT, ST                                                    |
+ *  | 2    | PC                                                        |
+ *  | 1    | SP                                                        |
+ *  | 32   | stack, 16 words                                           |
+ *  | 2    | keypad, bit N set while key N is held                     |
+ *  | 256  | display, one 64-bit word per row, MSB = leftmost pixel    |
+ *  | 1    | key `FX0A` is waiting on to be released, 0xFF for none    |
+ *  | 1+2  | whether `FX0A` is waiting for a press, and the keys held  |
+ *  |      | when it started, bit N for key N                          |
+ *  | 1    | vertical blank wait flag                                  |
+ *  | 6    | quirks: shift_vx, load_store, jump_with_vx, vf_reset,     |
+ *  |      | wrap_sprites, display_wait                                |
+ *  | 8    | random number generator state                             |
+ */
+
+use std::io;
+use std::io::prelude::*;
+
+use sha1::Sha1;
+
+use chip8::{DISPLAY_WIDTH, DISPLAY_HEIGHT, MEMORY_SIZE};
+use error::StateError;
+use quirks::{IndexIncrement, Quirks};
+
+/// Identifies the start of a save state file.
+pub const STATE_MAGIC: [u8; 4] = *b"C8ST";
+/// Current save state format. Bump whenever the layout changes.
+pub const STATE_VERSION: u16 = 2;
+/// Size of an encoded save state in bytes.
+pub const STATE_SIZE: usize = 4 + 2 + 20 + MEMORY_SIZE + 16 + 2 + 2 + 2 + 1 + 32 + 2
+    + DISPLAY_HEIGHT * 8 + 1 + 3 + 1 + 6 + 8;
+
+/// The complete state of a machine, as captured by `Chip8::save_state`.
+#[derive(Clone, PartialEq)]
+pub struct SaveState {
+    /// SHA-1 of the ROM that was loaded, so the state is not restored over another program
+    pub rom_hash: [u8; 20],
+    pub memory: [u8; MEMORY_SIZE],
+    pub v: [u8; 16],
+    pub i: u16,
+    pub dt: u8,
+    pub st: u8,
+    pub pc: u16,
+    pub sp: u8,
+    pub stack: [u16; 16],
+    pub keyboard: [bool; 16],
+    pub display: [[bool; DISPLAY_WIDTH]; DISPLAY_HEIGHT],
+    pub key_wait: Option<u8>,
+    pub key_wait_held: Option<u16>,
+    pub vblank_wait: bool,
+    pub quirks: Quirks,
+    pub rng: u64,
+}
+
+/** SHA-1 of a ROM image, as stored in save states */
+pub fn rom_hash(rom: &[u8]) -> [u8; 20] {
+    Sha1::from(rom).digest().bytes()
+}
+
+impl SaveState {
+    /** Encodes the state in the format described at the top of this file */
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(STATE_SIZE);
+        out.extend_from_slice(&STATE_MAGIC);
+        put_u16(&mut out, STATE_VERSION);
+        out.extend_from_slice(&self.rom_hash);
+        out.extend_from_slice(&self.memory);
+        out.extend_from_slice(&self.v);
+        put_u16(&mut out, self.i);
+        out.push(self.dt);
+        out.push(self.st);
+        put_u16(&mut out, self.pc);
+        out.push(self.sp);
+        for &word in self.stack.iter() {
+            put_u16(&mut out, word);
+        }
+
+        let keys = self.keyboard.iter().enumerate()
+            .fold(0u16, |bits, (key, &down)| bits | (down as u16) << key);
+        put_u16(&mut out, keys);
+        for row in self.display.iter() {
+            let bits = row.iter().fold(0u64, |bits, &on| bits << 1 | on as u64);
+            out.extend_from_slice(&bits.to_be_bytes());
+        }
+
+        out.push(self.key_wait.unwrap_or(0xFF));
+        out.push(self.key_wait_held.is_some() as u8);
+        put_u16(&mut out, self.key_wait_held.unwrap_or(0));
+        out.push(self.vblank_wait as u8);
+
+        let q = &self.quirks;
+        let load_store = match q.load_store {
+            IndexIncrement::XPlusOne => 0,
+            IndexIncrement::X => 1,
+            IndexIncrement::None => 2,
+        };
+        out.extend_from_slice(&[q.shift_vx as u8, load_store, q.jump_with_vx as u8,
+                                q.vf_reset as u8, q.wrap_sprites as u8, q.display_wait as u8]);
+        out.extend_from_slice(&self.rng.to_be_bytes());
+
+        debug_assert_eq!(out.len(), STATE_SIZE);
+        out
+    }
+
+    /** Decodes a state written by `to_bytes`, checking the magic, version and
+     *  that every field holds a value the machine could actually be in */
+    pub fn from_bytes(bytes: &[u8]) -> Result<SaveState, StateError> {
+        if bytes.len() < 6 || bytes[..4] != STATE_MAGIC {
+            return Err(StateError::NotAState);
+        }
+        let version = u16::from_be_bytes([bytes[4], bytes[5]]);
+        if version != STATE_VERSION {
+            return Err(StateError::Version(version));
+        }
+        if bytes.len() != STATE_SIZE {
+            return Err(StateError::Corrupt("wrong size"));
+        }
+
+        let mut r = Reader { bytes, pos: 6 };
+        let mut state = SaveState {
+            rom_hash: [0; 20],
+            memory: [0; MEMORY_SIZE],
+            v: [0; 16],
+            i: 0,
+            dt: 0,
+            st: 0,
+            pc: 0,
+            sp: 0,
+            stack: [0; 16],
+            keyboard: [false; 16],
+            display: [[false; DISPLAY_WIDTH]; DISPLAY_HEIGHT],
+            key_wait: None,
+            key_wait_held: None,
+            vblank_wait: false,
+            quirks: Quirks::default(),
+            rng: 0,
+        };
+
+        state.rom_hash.copy_from_slice(r.take(20));
+        state.memory.copy_from_slice(r.take(MEMORY_SIZE));
+        state.v.copy_from_slice(r.take(16));
+        state.i = r.u16();
+        state.dt = r.u8();
+        state.st = r.u8();
+        state.pc = r.u16();
+        state.sp = r.u8();
+        for word in state.stack.iter_mut() {
+            *word = r.u16();
+        }
+        if state.sp as usize > state.stack.len() {
+            return Err(StateError::Corrupt("stack pointer out of range"));
+        }
+
+        let keys = r.u16();
+        for (key, down) in state.keyboard.iter_mut().enumerate() {
+            *down = keys & (1 << key) != 0;
+        }
+        for row in state.display.iter_mut() {
+            let bits = r.u64();
+            for (x, pixel) in row.iter_mut().enumerate() {
+                *pixel = bits & (1 << (DISPLAY_WIDTH - 1 - x)) != 0;
+            }
+        }
+
+        state.key_wait = match r.u8() {
+            0xFF => None,
+            key if key <= 0xF => Some(key),
+            _ => return Err(StateError::Corrupt("invalid key wait")),
+        };
+        let waiting = r.flag()?;
+        let held = r.u16();
+        state.key_wait_held = if waiting { Some(held) } else { None };
+        state.vblank_wait = r.flag()?;
+
+        state.quirks.shift_vx = r.flag()?;
+        state.quirks.load_store = match r.u8() {
+            0 => IndexIncrement::XPlusOne,
+            1 => IndexIncrement::X,
+            2 => IndexIncrement::None,
+            _ => return Err(StateError::Corrupt("invalid load/store quirk")),
+        };
+        state.quirks.jump_with_vx = r.flag()?;
+        state.quirks.vf_reset = r.flag()?;
+        state.quirks.wrap_sprites = r.flag()?;
+        state.quirks.display_wait = r.flag()?;
+        state.rng = r.u64();
+
+        Ok(state)
+    }
+
+    /** Writes the encoded state to `out` */
+    pub fn write_to<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        out.write_all(&self.to_bytes())
+    }
+
+    /** Reads and decodes a state from `input` */
+    pub fn read_from<R: Read>(input: &mut R) -> Result<SaveState, StateError> {
+        let mut bytes = Vec::with_capacity(STATE_SIZE);
+        input.read_to_end(&mut bytes)?;
+        SaveState::from_bytes(&bytes)
+    }
+}
+
+fn put_u16(out: &mut Vec<u8>, value: u16) {
+    out.extend_from_slice(&value.to_be_bytes());
+}
+
+/// Cursor over an encoded state whose length has already been checked.
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> &'a [u8] {
+        let slice = &self.bytes[self.pos..self.pos + len];
+        self.pos += len;
+        slice
+    }
+
+    fn u8(&mut self) -> u8 {
+        self.take(1)[0]
+    }
+
+    fn u16(&mut self) -> u16 {
+        let b = self.take(2);
+        u16::from_be_bytes([b[0], b[1]])
+    }
+
+    fn u64(&mut self) -> u64 {
+        let mut b = [0; 8];
+        b.copy_from_slice(self.take(8));
+        u64::from_be_bytes(b)
+    }
+
+    fn flag(&mut self) -> Result<bool, StateError> {
+        match self.u8() {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(StateError::Corrupt("invalid flag")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chip8::Chip8;
+
+    const ROM: &[u8] = include_bytes!("../IBM Logo.ch8");
+
+    fn running_core() -> Chip8 {
+        let mut core = Chip8::new();
+        core.load_rom_bytes(ROM).unwrap();
+        for _ in 0..50 {
+            core.step().unwrap();
+        }
+        core
+    }
+
+    #[test]
+    fn round_trip() {
+        let core = running_core();
+        let state = core.save_state();
+        let bytes = state.to_bytes();
+        assert_eq!(bytes.len(), STATE_SIZE);
+        let decoded = SaveState::from_bytes(&bytes).unwrap();
+        assert!(decoded == state);
+
+        let mut restored = Chip8::new();
+        restored.load_rom_bytes(ROM).unwrap();
+        restored.load_state(&decoded).unwrap();
+        assert!(restored.save_state() == state);
+    }
+
+    #[test]
+    fn wrong_magic() {
+        let mut bytes = running_core().save_state().to_bytes();
+        bytes[0] = b'X';
+        assert!(matches!(SaveState::from_bytes(&bytes), Err(StateError::NotAState)));
+        assert!(matches!(SaveState::from_bytes(b"C8"), Err(StateError::NotAState)));
+    }
+
+    #[test]
+    fn newer_version() {
+        let mut bytes = running_core().save_state().to_bytes();
+        bytes[4..6].copy_from_slice(&(STATE_VERSION + 1).to_be_bytes());
+        assert!(matches!(SaveState::from_bytes(&bytes), Err(StateError::Version(v)) if v == STATE_VERSION + 1));
+    }
+
+    #[test]
+    fn truncated() {
+        let bytes = running_core().save_state().to_bytes();
+        assert!(matches!(SaveState::from_bytes(&bytes[..STATE_SIZE - 1]), Err(StateError::Corrupt(_))));
+        assert!(matches!(SaveState::from_bytes(&bytes[..6]), Err(StateError::Corrupt(_))));
+    }
+
+    #[test]
+    fn different_rom() {
+        let state = running_core().save_state();
+        let mut other = Chip8::new();
+        other.load_rom_bytes(&ROM[..ROM.len() - 2]).unwrap();
+        assert!(matches!(other.load_state(&state), Err(StateError::RomMismatch)));
+    }
+}
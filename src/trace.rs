@@ -0,0 +1,281 @@
+/*  trace.rs
+ *  Instruction trace log. When a `Tracer` is attached with
+ *  `Chip8::set_trace`, every executed instruction that passes its filters is
+ *  logged with the cycle number, PC, opcode, mnemonic and the registers it
+ *  changed, either as aligned text or as JSON Lines. With no tracer attached
+ *  the core does no tracing work at all beyond checking for one. */
+
+use std::io::prelude::*;
+
+use serde_json::{self, Value};
+
+use error::Chip8Error;
+use instruction::Instruction;
+
+/// How trace records are written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceFormat {
+    /// One aligned, human-readable line per instruction
+    Text,
+    /// One JSON object per line
+    Json,
+}
+
+impl TraceFormat {
+    /** Parses `text` or `json` */
+    pub fn from_name(name: &str) -> Option<TraceFormat> {
+        match name {
+            "text" => Some(TraceFormat::Text),
+            "json" | "jsonl" => Some(TraceFormat::Json),
+            _ => None,
+        }
+    }
+}
+
+/// Matches opcodes against a pattern like `DXYN` or `F?33`: hex digits must
+/// match, any other character matches any nibble.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OpcodePattern {
+    mask: u16,
+    value: u16,
+}
+
+impl OpcodePattern {
+    /** Parses a four-character pattern */
+    pub fn parse(pattern: &str) -> Option<OpcodePattern> {
+        if pattern.chars().count() != 4 {
+            return None;
+        }
+        let (mut mask, mut value) = (0, 0);
+        for c in pattern.chars() {
+            mask <<= 4;
+            value <<= 4;
+            if let Some(digit) = c.to_digit(16) {
+                mask |= 0xF;
+                value |= digit as u16;
+            }
+        }
+        Some(OpcodePattern { mask, value })
+    }
+
+    pub fn matches(&self, opcode: u16) -> bool {
+        opcode & self.mask == self.value
+    }
+}
+
+/// Register values an instruction may change, captured before it runs.
+#[derive(Clone, Copy)]
+pub struct Registers {
+    pub v: [u8; 16],
+    pub i: u16,
+    pub dt: u8,
+    pub st: u8,
+    pub sp: u8,
+}
+
+/// Writes trace records for the instructions that pass its filters.
+pub struct Tracer {
+    out: Box<dyn Write>,
+    format: TraceFormat,
+    /// Inclusive PC ranges to trace; empty traces everywhere
+    ranges: Vec<(u16, u16)>,
+    /// Opcodes to trace; empty traces every opcode
+    opcodes: Vec<OpcodePattern>,
+}
+
+impl Tracer {
+    pub fn new(out: Box<dyn Write>, format: TraceFormat) -> Tracer {
+        Tracer { out, format, ranges: Vec::new(), opcodes: Vec::new() }
+    }
+
+    /** Only traces instructions whose address is in one of the added ranges */
+    pub fn add_range(&mut self, start: u16, end: u16) {
+        self.ranges.push((start, end));
+    }
+
+    /** Only traces opcodes matching one of the added patterns */
+    pub fn add_opcode(&mut self, pattern: OpcodePattern) {
+        self.opcodes.push(pattern);
+    }
+
+    /** Whether the instruction `opcode` at `pc` passes the filters */
+    pub fn wants(&self, pc: u16, opcode: u16) -> bool {
+        (self.ranges.is_empty() || self.ranges.iter().any(|&(start, end)| pc >= start && pc <= end))
+            && (self.opcodes.is_empty() || self.opcodes.iter().any(|pattern| pattern.matches(opcode)))
+    }
+
+    /** Logs one instruction. `fault` is set if it faulted rather than ran. */
+    pub fn record(&mut self, cycle: u64, pc: u16, opcode: u16, before: &Registers, after: &Registers,
+                  fault: Option<&Chip8Error>) {
+        let mnemonic = match Instruction::decode(opcode) {
+            Some(instr) => instr.to_string(),
+            None => "???".to_string(),
+        };
+        let changes = changes(before, after);
+
+        // Trace output is best effort: a full disk must not stop the machine
+        let _ = match self.format {
+            TraceFormat::Text => {
+                let changes: Vec<String> = changes.iter()
+                    .map(|&(name, value)| format!("{}={:X}", name, value))
+                    .collect();
+                let mut line = format!("{:>10} {:03X}: {:04X}  {:<16} {}", cycle, pc, opcode, mnemonic, changes.join(" "));
+                if let Some(fault) = fault {
+                    line.push_str(&format!("  ! {}", fault));
+                }
+                writeln!(self.out, "{}", line.trim_end())
+            },
+            TraceFormat::Json => {
+                let changes: serde_json::Map<String, Value> = changes.iter()
+                    .map(|&(name, value)| (name.to_string(), json!(value)))
+                    .collect();
+                let mut record = json!({
+                    "cycle": cycle,
+                    "pc": pc,
+                    "opcode": opcode,
+                    "mnemonic": mnemonic,
+                    "changes": changes,
+                });
+                if let Some(fault) = fault {
+                    record["fault"] = json!(fault.to_string());
+                }
+                writeln!(self.out, "{}", record)
+            },
+        };
+    }
+}
+
+const V_NAMES: [&str; 16] = ["V0", "V1", "V2", "V3", "V4", "V5", "V6", "V7",
+                             "V8", "V9", "VA", "VB", "VC", "VD", "VE", "VF"];
+
+/** The registers that differ between `before` and `after`, with their new values */
+fn changes(before: &Registers, after: &Registers) -> Vec<(&'static str, u16)> {
+    let mut changes = Vec::new();
+    for (x, name) in V_NAMES.iter().enumerate() {
+        if before.v[x] != after.v[x] {
+            changes.push((*name, after.v[x] as u16));
+        }
+    }
+    if before.i != after.i {
+        changes.push(("I", after.i));
+    }
+    if before.dt != after.dt {
+        changes.push(("DT", after.dt as u16));
+    }
+    if before.st != after.st {
+        changes.push(("ST", after.st as u16));
+    }
+    if before.sp != after.sp {
+        changes.push(("SP", after.sp as u16));
+    }
+    changes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::io;
+    use std::rc::Rc;
+
+    /// A trace output the test can read back after handing it to a `Tracer`
+    #[derive(Clone, Default)]
+    struct Shared(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Shared {
+        fn text(&self) -> String {
+            String::from_utf8(self.0.borrow().clone()).unwrap()
+        }
+    }
+
+    fn registers() -> Registers {
+        Registers { v: [0; 16], i: 0, dt: 0, st: 0, sp: 0 }
+    }
+
+    #[test]
+    fn opcode_patterns() {
+        let exact = OpcodePattern::parse("00E0").unwrap();
+        assert!(exact.matches(0x00E0));
+        assert!(!exact.matches(0x00EE));
+
+        let draw = OpcodePattern::parse("DXYN").unwrap();
+        assert!(draw.matches(0xD125));
+        assert!(draw.matches(0xDFFF));
+        assert!(!draw.matches(0xC125));
+
+        let bcd = OpcodePattern::parse("f?33").unwrap();
+        assert!(bcd.matches(0xF333));
+        assert!(!bcd.matches(0xF355));
+
+        assert_eq!(OpcodePattern::parse("D12"), None);
+        assert_eq!(OpcodePattern::parse("D1234"), None);
+        assert_eq!(OpcodePattern::parse(""), None);
+    }
+
+    #[test]
+    fn filters() {
+        let mut tracer = Tracer::new(Box::new(io::sink()), TraceFormat::Text);
+        assert!(tracer.wants(0x200, 0x1234));
+
+        tracer.add_range(0x210, 0x21F);
+        tracer.add_range(0x300, 0x300);
+        assert!(!tracer.wants(0x20E, 0x1234));
+        assert!(tracer.wants(0x210, 0x1234));
+        assert!(tracer.wants(0x21F, 0x1234));
+        assert!(!tracer.wants(0x220, 0x1234));
+        assert!(tracer.wants(0x300, 0x1234));
+
+        // Both filters must pass
+        tracer.add_opcode(OpcodePattern::parse("DXYN").unwrap());
+        assert!(!tracer.wants(0x210, 0x1234));
+        assert!(tracer.wants(0x210, 0xD015));
+        assert!(!tracer.wants(0x200, 0xD015));
+    }
+
+    #[test]
+    fn text_record() {
+        let out = Shared::default();
+        let mut tracer = Tracer::new(Box::new(out.clone()), TraceFormat::Text);
+        let before = registers();
+        let mut after = registers();
+        after.v[0xA] = 0x2B;
+        after.i = 0x123;
+        tracer.record(42, 0x204, 0x6A2B, &before, &after, None);
+        tracer.record(43, 0x206, 0x00EE, &after, &after, Some(&Chip8Error::StackUnderflow { pc: 0x206 }));
+        assert_eq!(out.text(), "        42 204: 6A2B  LD VA, 0x2B      VA=2B I=123\n\
+                                \x20       43 206: 00EE  RET                ! return with empty stack at 206\n");
+    }
+
+    #[test]
+    fn json_record() {
+        let out = Shared::default();
+        let mut tracer = Tracer::new(Box::new(out.clone()), TraceFormat::Json);
+        let before = registers();
+        let mut after = registers();
+        after.v[0xA] = 0x2B;
+        after.dt = 60;
+        tracer.record(42, 0x204, 0x6A2B, &before, &after, None);
+
+        let text = out.text();
+        assert_eq!(text, "{\"changes\":{\"DT\":60,\"VA\":43},\"cycle\":42,\"mnemonic\":\"LD VA, 0x2B\",\"opcode\":27179,\"pc\":516}\n");
+        let record: Value = serde_json::from_str(text.trim_end()).unwrap();
+        assert_eq!(record["cycle"], 42);
+        assert_eq!(record["pc"], 0x204);
+        assert_eq!(record["opcode"], 0x6A2B);
+        assert_eq!(record["mnemonic"], "LD VA, 0x2B");
+        assert_eq!(record["changes"]["VA"], 0x2B);
+        assert_eq!(record["changes"]["DT"], 60);
+        assert!(record.get("fault").is_none());
+    }
+}
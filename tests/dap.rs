@@ -0,0 +1,167 @@
+/*  dap.rs
+ *  Drives a Debug Adapter Protocol session through a launch, a breakpoint
+ *  stop and a step, the way an editor would. */
+
+extern crate chip8remu;
+#[macro_use]
+extern crate serde_json;
+
+use std::collections::VecDeque;
+use std::io::{self, BufReader, PipeReader, PipeWriter};
+use std::thread;
+
+use serde_json::Value;
+
+use chip8remu::Chip8;
+use chip8remu::dap::{self, read_message, write_message};
+
+struct Client {
+    requests: PipeWriter,
+    messages: BufReader<PipeReader>,
+    seq: u64,
+    /// Events read while waiting for a response
+    events: VecDeque<Value>,
+}
+
+impl Client {
+    fn read(&mut self) -> Value {
+        read_message(&mut self.messages).unwrap().expect("the server closed the connection")
+    }
+
+    /** Sends a request and returns the body of its successful response */
+    fn request(&mut self, command: &str, arguments: Value) -> Value {
+        self.seq += 1;
+        let request = json!({ "seq": self.seq, "type": "request", "command": command, "arguments": arguments });
+        write_message(&mut self.requests, &request).unwrap();
+        loop {
+            let message = self.read();
+            if message["type"] == "event" {
+                self.events.push_back(message);
+                continue;
+            }
+            assert_eq!(message["request_seq"], self.seq);
+            assert_eq!(message["command"], command);
+            assert_eq!(message["success"], true, "{}", message);
+            return message["body"].clone();
+        }
+    }
+
+    /** Waits for the next event, which must be `name`, and returns its body */
+    fn event(&mut self, name: &str) -> Value {
+        let event = match self.events.pop_front() {
+            Some(event) => event,
+            None => self.read(),
+        };
+        assert_eq!(event["event"], name, "{}", event);
+        event["body"].clone()
+    }
+}
+
+fn variable<'a>(variables: &'a Value, name: &str) -> &'a str {
+    variables["variables"].as_array().unwrap().iter()
+        .find(|v| v["name"] == name)
+        .and_then(|v| v["value"].as_str())
+        .unwrap_or_else(|| panic!("no variable {}", name))
+}
+
+#[test]
+fn breakpoint_and_step() {
+    let (server_in, requests) = io::pipe().unwrap();
+    let (messages, server_out) = io::pipe().unwrap();
+    let server = thread::spawn(move || {
+        dap::serve_on(BufReader::new(server_in), server_out, |_| Ok(Chip8::new()))
+    });
+    let mut client = Client { requests, messages: BufReader::new(messages), seq: 0, events: VecDeque::new() };
+
+    let capabilities = client.request("initialize", json!({ "adapterID": "chip8remu" }));
+    assert_eq!(capabilities["supportsConfigurationDoneRequest"], true);
+    client.event("initialized");
+
+    let rom = concat!(env!("CARGO_MANIFEST_DIR"), "/IBM Logo.ch8");
+    client.request("launch", json!({ "program": rom }));
+
+    // Line 6 of the listing is the word at 0x20A, `ADD V0, 0x09`
+    let breakpoints = client.request("setBreakpoints", json!({
+        "source": { "sourceReference": 1 },
+        "breakpoints": [{ "line": 6 }, { "line": 1000 }],
+    }));
+    assert_eq!(breakpoints["breakpoints"][0]["verified"], true);
+    assert_eq!(breakpoints["breakpoints"][1]["verified"], false);
+
+    client.request("configurationDone", Value::Null);
+    let stopped = client.event("stopped");
+    assert_eq!(stopped["reason"], "breakpoint");
+    assert_eq!(stopped["threadId"], 1);
+
+    let trace = client.request("stackTrace", json!({ "threadId": 1 }));
+    assert_eq!(trace["totalFrames"], 1);
+    let frame = &trace["stackFrames"][0];
+    assert_eq!(frame["name"], "main");
+    assert_eq!(frame["line"], 6);
+    assert_eq!(frame["instructionPointerReference"], "0x20A");
+    assert_eq!(frame["source"]["name"], "IBM Logo.ch8 (disassembly)");
+
+    let scopes = client.request("scopes", json!({ "frameId": 0 }));
+    assert_eq!(scopes["scopes"][0]["name"], "Registers");
+    let registers_ref = scopes["scopes"][0]["variablesReference"].clone();
+    let registers = client.request("variables", json!({ "variablesReference": registers_ref }));
+    assert_eq!(variable(&registers, "V0"), "0x0C");
+    assert_eq!(variable(&registers, "V1"), "0x08");
+    assert_eq!(variable(&registers, "I"), "0x22A");
+    assert_eq!(variable(&registers, "PC"), "0x20A");
+
+    // Pausing while stopped stops nothing; the next event is the step's
+    client.request("pause", json!({ "threadId": 1 }));
+    client.request("next", json!({ "threadId": 1 }));
+    assert_eq!(client.event("stopped")["reason"], "step");
+    let registers = client.request("variables", json!({ "variablesReference": registers_ref }));
+    assert_eq!(variable(&registers, "V0"), "0x15");
+    assert_eq!(variable(&registers, "PC"), "0x20C");
+
+    client.request("disconnect", json!({}));
+    assert_eq!(server.join().unwrap(), Ok(()));
+}
+
+#[test]
+fn breakpoints_set_before_launch() {
+    let (server_in, requests) = io::pipe().unwrap();
+    let (messages, server_out) = io::pipe().unwrap();
+    let server = thread::spawn(move || {
+        dap::serve_on(BufReader::new(server_in), server_out, |_| Ok(Chip8::new()))
+    });
+    let mut client = Client { requests, messages: BufReader::new(messages), seq: 0, events: VecDeque::new() };
+
+    client.request("initialize", json!({ "adapterID": "chip8remu" }));
+    client.event("initialized");
+
+    // Lines cannot be checked until there is a ROM
+    let breakpoints = client.request("setBreakpoints", json!({
+        "source": { "sourceReference": 1 },
+        "breakpoints": [{ "line": 6 }, { "line": 1000 }],
+    }));
+    assert_eq!(breakpoints["breakpoints"][0]["verified"], false);
+    let (id, outside) = (breakpoints["breakpoints"][0]["id"].clone(), breakpoints["breakpoints"][1]["id"].clone());
+
+    let rom = concat!(env!("CARGO_MANIFEST_DIR"), "/IBM Logo.ch8");
+    client.request("launch", json!({ "program": rom }));
+    let changed = client.event("breakpoint");
+    assert_eq!(changed["reason"], "changed");
+    assert_eq!(changed["breakpoint"]["id"], id);
+    assert_eq!(changed["breakpoint"]["verified"], true);
+    let changed = client.event("breakpoint");
+    assert_eq!(changed["breakpoint"]["id"], outside);
+    assert_eq!(changed["breakpoint"]["verified"], false);
+
+    client.request("configurationDone", Value::Null);
+    assert_eq!(client.event("stopped")["reason"], "breakpoint");
+    let trace = client.request("stackTrace", json!({ "threadId": 1 }));
+    assert_eq!(trace["stackFrames"][0]["line"], 6);
+
+    // The logo ends in an endless loop, which only a pause stops
+    client.request("continue", json!({ "threadId": 1 }));
+    client.request("pause", json!({ "threadId": 1 }));
+    assert_eq!(client.event("stopped")["reason"], "pause");
+
+    client.request("disconnect", json!({}));
+    assert_eq!(server.join().unwrap(), Ok(()));
+}
@@ -0,0 +1,29 @@
+/*  disasm.rs
+ *  The Octo listing of every bundled ROM must assemble back to the ROM. */
+
+extern crate chip8remu;
+
+use std::fs;
+
+use chip8remu::asm;
+use chip8remu::disasm::{Disassembly, Syntax};
+
+#[test]
+fn octo_listing_reassembles() {
+    let mut roms: Vec<_> = fs::read_dir(env!("CARGO_MANIFEST_DIR")).unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "ch8"))
+        .collect();
+    roms.sort();
+    assert!(!roms.is_empty());
+
+    for path in roms {
+        let rom = fs::read(&path).unwrap();
+        let mut listing = Vec::new();
+        Disassembly::analyze(&rom).write_listing(&mut listing, Syntax::Octo).unwrap();
+        let listing = String::from_utf8(listing).unwrap();
+        let assembly = asm::assemble(&listing)
+            .unwrap_or_else(|e| panic!("{}: {}\n{}", path.display(), e, listing));
+        assert!(assembly.bytes == rom, "{} does not reassemble to the same bytes", path.display());
+    }
+}